@@ -7,6 +7,7 @@ pub extern crate gui;
 
 pub mod synth;
 pub mod voice;
+pub mod state;
 pub mod editor;
 
 #[derive(Clone, Copy)]
@@ -0,0 +1,63 @@
+
+// El estado del plugin que guarda el host es una lista de bytes. Todo se
+// escribe en little endian, para que un estado guardado en una máquina se
+// pueda abrir en otra.
+
+pub struct Writer {
+  data: Vec<u8>,
+}
+
+impl Writer {
+  pub fn new () -> Writer { Writer { data: Vec::new() } }
+
+  pub fn u32 (&mut self, value: u32) {
+    for i in 0..4 { self.data.push((value >> (8*i)) as u8); }
+  }
+
+  pub fn f32 (&mut self, value: f32) {
+    self.u32(value.to_bits());
+  }
+
+  pub fn bytes (&mut self, bytes: &[u8]) {
+    self.data.extend_from_slice(bytes);
+  }
+
+  pub fn into_bytes (self) -> Vec<u8> { self.data }
+}
+
+/// Los datos se acabaron antes de tiempo.
+#[derive(Debug)]
+pub struct EndOfData;
+
+/// Lee lo que escribió un Writer. Si los datos se acaban devuelve un
+/// error, así un estado roto o de otra versión no hace caer al plugin.
+pub struct Reader<'a> {
+  data: &'a [u8],
+  pos: usize,
+}
+
+impl<'a> Reader<'a> {
+  pub fn new (data: &'a [u8]) -> Reader<'a> { Reader { data: data, pos: 0 } }
+
+  pub fn u32 (&mut self) -> Result<u32, EndOfData> {
+    if self.pos + 4 > self.data.len() { return Err(EndOfData); }
+    let mut value = 0;
+    for i in 0..4 { value |= (self.data[self.pos + i] as u32) << (8*i); }
+    self.pos += 4;
+    Ok(value)
+  }
+
+  pub fn f32 (&mut self) -> Result<f32, EndOfData> {
+    self.u32().map(f32::from_bits)
+  }
+
+  /// Los siguientes len bytes.
+  pub fn bytes (&mut self, len: usize) -> Result<&'a [u8], EndOfData> {
+    if self.pos + len > self.data.len() { return Err(EndOfData); }
+    self.pos += len;
+    Ok(&self.data[self.pos - len .. self.pos])
+  }
+
+  /// Todo lo que queda sin leer.
+  pub fn rest (&self) -> &'a [u8] { &self.data[self.pos..] }
+}
@@ -8,6 +8,7 @@ use vst2::plugin::CanDo;
 use vst2::api::Supported;
 
 use editor::PluginEditor;
+use state::{Writer, Reader};
 
 use std::sync::{Arc, Mutex, MutexGuard, mpsc};
 
@@ -68,17 +69,43 @@ pub trait Synth : Send {
   fn events(&mut self, events: Vec<Event>) {}
   //fn event(&mut self, event: Event) {}
 
-  fn note_on(&mut self, note: u8, vel: u8) {}
-  fn note_off(&mut self, note: u8) {}
+  fn note_on(&mut self, channel: u8, note: u8, vel: u8) {}
+  fn note_off(&mut self, channel: u8, note: u8) {}
 
   fn set_param(&mut self, index: usize, value: f32) {}
-  fn param_name(index: usize) -> String { format!("Parameter {}", index) }
+  /// Parámetro que controla un Control Change de midi, si hay alguno.
+  fn cc_param(cc: u8) -> Option<usize> { None }
+  fn program_change(&mut self, channel: u8, program: u8) {}
+  /// Parámetros que el sintetizador cambió por su cuenta, por ejemplo al
+  /// recibir un Program Change. El plugin los pide después de cada evento
+  /// y al final de cada bloque, para que el host y el editor se enteren.
+  /// Se escriben en out, que el plugin ya tiene reservado porque esto
+  /// corre en el hilo de audio, y devuelve cuántos escribió. Los que no
+  /// caben se quedan para la próxima vez.
+  fn changed_params(&mut self, out: &mut [(usize, f32)]) -> usize { 0 }
+  /// Recibe el sintetizador porque los nombres pueden depender de lo que
+  /// se cargó, como los registros de un órgano importado. El plugin los
+  /// pide al crearse y al cargar un estado, y los guarda, así que no
+  /// deben depender del valor de los parámetros.
+  fn param_name(&self, index: usize) -> String { format!("Parameter {}", index) }
+  /// Si un parámetro se restaura al cargar un estado. Los que actúan al
+  /// cambiar, como un pedal que alterna algo al presionarse, no deben
+  /// repetir lo que hicieron cuando se guardó el estado.
+  fn param_restored(index: usize) -> bool { true }
   fn param_default(index: usize) -> f32 { 0.0f32 }
   fn param_label(index: usize, value: f32) -> String { format!("{}", value) }
 
   fn arch_change(&mut self, arch: Architecture) {}
+
+  /// Estado que no está en los parámetros, el host lo guarda con el
+  /// proyecto junto con los parámetros.
+  fn get_state(&self) -> Vec<u8> { Vec::new() }
+  fn set_state(&mut self, data: &[u8]) {}
 }
 
+/// Marca del formato del estado guardado, por si cambia.
+const STATE_VERSION: u32 = 1;
+
 /*fn param_thread <T: Synth + 'static> (
   receiver: mpsc::Receiver<ParamEvent>,
   synth_mutex: Arc<Mutex<T>>
@@ -98,6 +125,12 @@ pub trait Synth : Send {
 pub struct SynthPlugin<T: Synth> {
   synth: Arc<Mutex<T>>,
   params: Arc<Mutex< Vec<f32> >>,
+  /// Los nombres de los parámetros, para no tener que bloquear el
+  /// sintetizador mientras el hilo de audio lo usa.
+  names: Vec<String>,
+  /// Donde el sintetizador escribe los parámetros que cambió, ver
+  /// Synth::changed_params
+  changed: Vec<(usize, f32)>,
   events: Vec<Event>,
   arch: Architecture,
   editor: PluginEditor<T::Editor>,
@@ -118,6 +151,8 @@ impl<T: Synth + 'static> Plugin for SynthPlugin<T> {
       inputs: 0,
       outputs: 2,
 
+      preset_chunks: true,
+
       ..Default::default()
     }
   }
@@ -151,7 +186,7 @@ impl<T: Synth + 'static> Plugin for SynthPlugin<T> {
     {
       let mut params = params.lock().unwrap();
 
-      for i in 0..(info.params-1) {
+      for i in 0..info.params {
         let value = T::param_default(i);
         synth.set_param(i, value);
         editor.set_param(i, value);
@@ -159,6 +194,8 @@ impl<T: Synth + 'static> Plugin for SynthPlugin<T> {
       }
     }
 
+    let names = (0..info.params).map(|i| synth.param_name(i)).collect();
+
     let mutex = Arc::new(Mutex::new(synth));
 
     //param_thread(receiver, mutex.clone());
@@ -166,6 +203,8 @@ impl<T: Synth + 'static> Plugin for SynthPlugin<T> {
     SynthPlugin{
       synth: mutex,
       params: params,
+      names: names,
+      changed: vec![(0, 0.0); info.params],
       events: Vec::new(),
       arch: arch,
       editor: editor,
@@ -187,7 +226,12 @@ impl<T: Synth + 'static> Plugin for SynthPlugin<T> {
     self.editor.set_param(index, value);
   }
 
-  fn get_parameter_name(&self, index: i32) -> String { T::param_name(index as usize) }
+  fn get_parameter_name(&self, index: i32) -> String {
+    match self.names.get(index as usize) {
+      Some(name) => name.clone(),
+      None => String::new(),
+    }
+  }
 
   fn set_sample_rate(&mut self, rate: f32) {
     self.arch.sample_rate = rate;
@@ -229,13 +273,28 @@ impl<T: Synth + 'static> Plugin for SynthPlugin<T> {
     let mut last_event = 0;
     for event in self.events.drain(..) {
 
-      match event.data[0] {
-        0x90 if event.data[2]==0 => synth.note_off(event.data[1]),
-        0x90 => synth.note_on(event.data[1], event.data[2]),
-        0x80 => synth.note_off(event.data[1]),
+      // Los 4 bits bajos del status son el canal
+      let channel = event.data[0] & 0x0F;
+      match event.data[0] & 0xF0 {
+        0x90 if event.data[2]==0 => synth.note_off(channel, event.data[1]),
+        0x90 => synth.note_on(channel, event.data[1], event.data[2]),
+        0x80 => synth.note_off(channel, event.data[1]),
+        0xB0 => if let Some(i) = T::cc_param(event.data[1]) {
+          let value = event.data[2] as f32 / 127.0;
+          self.params.lock().unwrap()[i] = value;
+          synth.set_param(i, value);
+          self.editor.set_param(i, value);
+        },
+        0xC0 => synth.program_change(channel, event.data[1]),
         _ => {}
       }
 
+      let count = synth.changed_params(&mut self.changed);
+      for &(i, value) in self.changed[..count].iter() {
+        self.params.lock().unwrap()[i] = value;
+        self.editor.set_param(i, value);
+      }
+
       // El número de samples hasta el siguiente evento
       let next_event = (
         // Si el siguiente evento está en el pasado,
@@ -258,6 +317,13 @@ impl<T: Synth + 'static> Plugin for SynthPlugin<T> {
       *lsample = l;
       *rsample = r;
     }
+
+    // Lo que el sintetizador cambió mientras sonaba
+    let count = synth.changed_params(&mut self.changed);
+    for &(i, value) in self.changed[..count].iter() {
+      self.params.lock().unwrap()[i] = value;
+      self.editor.set_param(i, value);
+    }
   }
 
   fn process_events(&mut self, events: Vec<VstEvent>) {
@@ -274,6 +340,61 @@ impl<T: Synth + 'static> Plugin for SynthPlugin<T> {
     }
   }
 
+  // El estado son los parámetros y después lo que el sintetizador
+  // guarde por su cuenta.
+  fn get_preset_data (&mut self) -> Vec<u8> {
+    let mut writer = Writer::new();
+    writer.u32(STATE_VERSION);
+
+    let params = self.params.lock().unwrap();
+    writer.u32(params.len() as u32);
+    for &value in params.iter() { writer.f32(value); }
+
+    writer.bytes(&self.synth.lock().unwrap().get_state());
+    writer.into_bytes()
+  }
+
+  fn get_bank_data (&mut self) -> Vec<u8> { self.get_preset_data() }
+
+  fn load_preset_data (&mut self, data: &[u8]) {
+    let mut reader = Reader::new(data);
+    match reader.u32() {
+      Ok(STATE_VERSION) => {},
+      _ => return,
+    }
+
+    let count = match reader.u32() {
+      Ok(count) => count as usize,
+      Err(_) => return,
+    };
+
+    let mut synth = self.synth.lock().unwrap();
+    {
+      let mut params = self.params.lock().unwrap();
+      for i in 0..count {
+        let value = match reader.f32() {
+          Ok(value) => value,
+          Err(_) => return,
+        };
+        // Un estado de una versión con más parámetros, o un parámetro
+        // que no se restaura y se queda como está.
+        if i >= params.len() || !T::param_restored(i) { continue; }
+        params[i] = value;
+        synth.set_param(i, value);
+        self.editor.set_param(i, value);
+      }
+    }
+
+    synth.set_state(reader.rest());
+
+    // Lo que se cargó puede tener otros nombres
+    for (i, name) in self.names.iter_mut().enumerate() {
+      *name = synth.param_name(i);
+    }
+  }
+
+  fn load_bank_data (&mut self, data: &[u8]) { self.load_preset_data(data); }
+
   fn get_editor (&mut self) -> Option<&mut VstEditor> {
     Some(&mut self.editor)
   }
@@ -1,5 +1,8 @@
 
-const NUM_VOICES: usize = 8;
+// Una tecla puede ocupar varias voces de la misma división, por ejemplo con
+// los acopladores de 16' y 4' de un órgano son tres. 32 alcanza para diez
+// dedos así, con algunas más para los releases.
+const NUM_VOICES: usize = 32;
 
 pub trait Voice : Default {
   fn is_active(&self) -> bool;
@@ -8,9 +11,15 @@ pub trait Voice : Default {
 #[derive(Default)]
 struct Container <T: Voice> {voice: T, note: u8, age: u16}
 
-#[derive(Default)]
+// Las voces van en el heap, con muchas un array no cabe en la pila.
 pub struct Manager<T: Voice> {
-  voices: [Container<T>; NUM_VOICES],
+  voices: Vec<Container<T>>,
+}
+
+impl<T: Voice> Default for Manager<T> {
+  fn default () -> Self {
+    Manager { voices: (0..NUM_VOICES).map(|_| Default::default()).collect() }
+  }
 }
 
 impl<T: Voice> Manager<T> {
@@ -46,12 +55,22 @@ impl<T: Voice> Manager<T> {
   }
 
   pub fn note_off (&mut self, note: u8) -> Option<&mut T> {
+    self.get(note)
+  }
+
+  /// La voz activa que está tocando una nota, si hay.
+  pub fn get (&mut self, note: u8) -> Option<&mut T> {
     // Debería haber máximo una voz por nota
     self.voices.iter_mut().find(
       |ref cont| cont.voice.is_active() && cont.note == note
     ).map(|cont| &mut cont.voice)
   }
 
+  /// Todas las voces, también las que no están sonando.
+  pub fn all_mut<'a> (&'a mut self) -> impl Iterator<Item=&'a mut T> {
+    self.voices.iter_mut().map(|cont| &mut cont.voice)
+  }
+
   pub fn iter<'a> (&'a self) -> impl Iterator<Item=&'a T> {
     self.voices.iter().filter_map(
       |ref cont| if cont.voice.is_active()
@@ -158,6 +158,76 @@ impl<P: Painter, F: Fn(f32)> Control for Slider<P, F> {
   }
 }
 
+/// Un botón que alterna entre 0 y 1 cada vez que se presiona.
+pub struct Toggle<P: Painter, F: Fn(f32)> {
+  x: i32,
+  y: i32,
+  w: i32,
+  h: i32,
+  handler: ::Handler,
+  painter: P,
+  callback: F,
+
+  value: f32,
+}
+
+impl<P: Painter, F: Fn(f32)> Toggle<P, F> {
+  pub fn new (
+    x: i32, y: i32,
+    w: i32, h: i32,
+    handler: ::Handler,
+    painter: P,
+    callback: F,
+  ) -> Toggle<P, F> {
+    Toggle {
+      x: x, y: y, w: w, h: h,
+      handler: handler,
+      painter: painter,
+      callback: callback,
+      value: 0.0,
+    }
+  }
+}
+
+impl<P: Painter, F: Fn(f32)> Component for Toggle<P, F> {
+  fn paint (&self, canvas: &mut ::Canvas) {
+    self.painter.paint(canvas, self.x, self.y);
+  }
+  fn event (&mut self, ev: ::Event) {
+    match ev {
+      ::Event::MouseDown(::MouseBtn::L, x, y) => {
+        if x > self.x &&
+          x < self.x + self.w &&
+          y > self.y &&
+          y < self.y + self.h {
+          let value = if self.value > 0.5 { 0.0 } else { 1.0 };
+          self.set_value(value);
+        }
+      },
+      _ => {}
+    }
+  }
+}
+
+impl<P: Painter, F: Fn(f32)> Control for Toggle<P, F> {
+  type Value = f32;
+  fn set_value (&mut self, value: f32) {
+    if value == self.value { return; }
+    (self.callback)(value);
+    self.update_value(value);
+  }
+
+  fn update_value (&mut self, value: f32) {
+    // El host puede mandar cualquier valor, pero solo se pinta encendido o
+    // apagado, igual que como lo interpreta el sintetizador.
+    let value = if value > 0.5 { 1.0 } else { 0.0 };
+    if value == self.value { return; }
+    self.painter.set_value(value);
+    self.value = value;
+    self.handler.repaint();
+  }
+}
+
 pub enum SeqDirection {Horizontal, Vertical}
 
 /// Pinta una sección diferente de la imagen por cada valor.
@@ -0,0 +1,160 @@
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use handoff;
+use pipe::Osc;
+use wavetable::Wavetable;
+use wind::Wind;
+
+/*
+
+Tubos con un espectro dado, para timbres que Color no alcanza a hacer. El
+espectro es la amplitud de cada armónico, empezando por la fundamental, y
+se convierte en tablas de onda (ver wavetable.rs).
+
+Las cuerdas (gambas) tienen muchos armónicos altos y una fundamental débil,
+y las flautas casi solo fundamental con un poco de segundo y tercer
+armónico. Con Color solo se puede elegir entre todos los armónicos o solo
+los impares, en proporciones fijas.
+
+Cada rango guarda su propio espectro, que empieza como el de su SpectrumDef
+y se puede editar mientras suena. Las tablas nuevas las calcula un solo hilo
+para todo el órgano (ver spawn_worker y handoff.rs), y mientras tanto el
+rango sigue sonando con las de antes. Las viejas vuelven al hilo para
+borrarse allá.
+
+*/
+
+/// Cuántos armónicos se pueden editar en un espectro.
+pub const SPECTRUM_SIZE: usize = 24;
+
+pub struct SpectrumDef {
+  /// Amplitud de cada armónico, el primero es la fundamental
+  pub harmonics: &'static [f32],
+}
+
+/// Una gamba: fundamental débil y muchos armónicos que bajan despacio.
+pub const STRING: SpectrumDef = SpectrumDef {
+  harmonics: &[
+    0.45, 0.8, 0.7, 0.62, 0.55, 0.5, 0.42, 0.38,
+    0.34, 0.3, 0.26, 0.22, 0.2, 0.17, 0.15, 0.13,
+    0.11, 0.1, 0.09, 0.08, 0.07, 0.06, 0.05, 0.04,
+  ],
+};
+
+/// Una flauta transversal, casi pura con algo de octava y quinta.
+pub const TRAVERSE: SpectrumDef = SpectrumDef {
+  harmonics: &[1.0, 0.25, 0.12, 0.04, 0.02],
+};
+
+/// Lo que comparte un espectro con el hilo que calcula sus tablas.
+struct Job {
+  /// Los armónicos que faltan calcular, solo los últimos
+  request: Option<[f32; SPECTRUM_SIZE]>,
+  /// Las tablas que calculó el hilo y el rango todavía no tomó
+  ready: Option<Wavetable>,
+  /// Las tablas que el rango dejó de usar, para borrar en el hilo
+  old: Option<Wavetable>,
+}
+
+/// El espectro editable de un rango, con sus tablas.
+pub struct Spectrum {
+  harmonics: [f32; SPECTRUM_SIZE],
+  tables: Wavetable,
+  job: Arc<Mutex<Job>>,
+  /// Si el espectro cambió y todavía no se pudo pedir
+  dirty: bool,
+}
+
+impl Spectrum {
+  pub fn new (def: &SpectrumDef) -> Spectrum {
+    let mut harmonics = [0.0; SPECTRUM_SIZE];
+    for (h, &amp) in harmonics.iter_mut().zip(def.harmonics.iter()) { *h = amp; }
+
+    Spectrum {
+      tables: tables(&harmonics),
+      harmonics: harmonics,
+      job: Arc::new(Mutex::new(Job { request: None, ready: None, old: None })),
+      dirty: false,
+    }
+  }
+
+  pub fn harmonics (&self) -> &[f32] { &self.harmonics }
+
+  pub fn tables (&self) -> &Wavetable { &self.tables }
+
+  /// Cambia la amplitud de un armónico, el 0 es la fundamental.
+  pub fn set_harmonic (&mut self, index: usize, value: f32) {
+    if index >= SPECTRUM_SIZE || self.harmonics[index] == value { return; }
+    self.harmonics[index] = value;
+    self.dirty = true;
+    self.poll();
+  }
+
+  /// Pide las tablas si el espectro cambió, y toma las que ya llegaron
+  /// devolviendo las de antes. Si el hilo tiene el lock lo intenta en la
+  /// próxima llamada.
+  pub fn poll (&mut self) {
+    let mut job = match handoff::try_lock(&self.job) {
+      Some(job) => job,
+      None => return,
+    };
+
+    if self.dirty {
+      job.request = Some(self.harmonics);
+      self.dirty = false;
+    }
+
+    // Las nuevas se quedan esperando hasta que el hilo se lleve las viejas
+    if job.old.is_some() { return; }
+    let arrived = match job.ready {
+      Some(ref mut tables) => { mem::swap(&mut self.tables, tables); true },
+      None => false,
+    };
+    if arrived { job.old = job.ready.take(); }
+  }
+}
+
+/// Empieza el hilo que calcula las tablas de todos los espectros del
+/// órgano. Termina cuando se borran los espectros.
+pub fn spawn_worker<'a, I: Iterator<Item=&'a Spectrum>> (spectra: I) {
+  let jobs: Vec<Arc<Mutex<Job>>> = spectra.map(|spectrum| spectrum.job.clone()).collect();
+  if jobs.is_empty() { return; }
+  thread::spawn(move || work(jobs));
+}
+
+fn work (jobs: Vec<Arc<Mutex<Job>>>) {
+  while jobs.iter().any(|job| Arc::strong_count(job) > 1) {
+    handoff::nap();
+    for job in jobs.iter() {
+      let (request, old) = {
+        let mut job = handoff::lock(job);
+        (job.request.take(), job.old.take())
+      };
+      drop(old);
+
+      if let Some(harmonics) = request {
+        let tables = tables(&harmonics);
+        // Si había otras que el rango no alcanzó a tomar, ya no sirven
+        let stale = mem::replace(&mut handoff::lock(job).ready, Some(tables));
+        drop(stale);
+      }
+    }
+  }
+}
+
+/// Las tablas de un espectro, sin los armónicos en cero del final, que
+/// solo agregarían tablas iguales.
+fn tables (harmonics: &[f32]) -> Wavetable {
+  let count = harmonics.iter().rposition(|&h| h != 0.0).map(|i| i+1).unwrap_or(0);
+  Wavetable::from_spectrum(&harmonics[..count])
+}
+
+pub fn clock (tables: &Wavetable, osc: &mut Osc, wind: &Wind) -> f32 {
+  let sample = tables.read(osc.table, osc.phase);
+
+  osc.phase += osc.delta * wind.pitch;
+  if osc.phase >= 1.0 { osc.phase -= 1.0; }
+
+  sample
+}
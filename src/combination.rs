@@ -0,0 +1,244 @@
+use arnaudio::state::{Writer, Reader, EndOfData};
+
+use division::MANUAL_COUNT;
+
+/*
+
+La combinación (combination action) guarda registraciones para cambiarlas
+de golpe con un botón (piston) mientras se toca. Los pistones generales
+guardan todo el órgano, y los divisionales solo los registros de un
+manual. Para guardar, el organista mantiene presionado Set y presiona el
+piston, igual que en un órgano real.
+
+El secuenciador es una lista de combinaciones que se recorre con Next y
+Previous, para preparar todos los cambios de una obra y tocarla
+presionando solo Next. Set más el botón Step guarda en el paso actual.
+
+Una combinación es el valor de cada parámetro que le toca, junto con su
+índice, así un estado guardado no aplica valores a otros parámetros si
+cambia cuáles guarda cada piston. Cada una tiene reservado desde el
+principio el espacio para todos sus parámetros, porque los pistones se
+presionan en el hilo de audio.
+
+*/
+
+pub const GENERAL_SLOTS: usize = 10;
+pub const DIVISIONAL_SLOTS: usize = 6;
+pub const STEP_COUNT: usize = 64;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Snapshot {
+  /// Los parámetros como (índice, valor)
+  pub values: Vec<(usize, f32)>,
+  /// Si tiene algo guardado. Una vacía no es lo mismo que una sin
+  /// parámetros.
+  pub stored: bool,
+}
+
+impl Snapshot {
+  fn new (size: usize) -> Snapshot {
+    Snapshot { values: Vec::with_capacity(size), stored: false }
+  }
+
+  // No con vec!, que clona y el clon no tiene lo reservado
+  fn list (count: usize, size: usize) -> Vec<Snapshot> {
+    (0..count).map(|_| Snapshot::new(size)).collect()
+  }
+
+  fn clear (&mut self) {
+    self.values.clear();
+    self.stored = false;
+  }
+}
+
+/// Un botón de la combinación.
+#[derive(Clone, Copy)]
+pub enum Piston {
+  General(usize),
+  Divisional(usize, usize),
+  /// El paso actual del secuenciador
+  Step,
+  Next,
+  Previous,
+}
+
+pub struct Memory {
+  pub general: Vec<Snapshot>,
+  /// Los divisionales de cada manual
+  pub divisional: Vec<Vec<Snapshot>>,
+  pub steps: Vec<Snapshot>,
+  /// El paso actual del secuenciador
+  pub step: usize,
+
+  /// Si el botón Set está presionado. No se guarda con el estado.
+  pub set: bool,
+}
+
+impl Memory {
+  /// Una memoria vacía, con lugar para general parámetros en los generales
+  /// y los pasos, y divisional en los divisionales.
+  pub fn new (general: usize, divisional: usize) -> Memory {
+    Memory {
+      general: Snapshot::list(GENERAL_SLOTS, general),
+      divisional: (0..MANUAL_COUNT).map(|_| Snapshot::list(DIVISIONAL_SLOTS, divisional)).collect(),
+      steps: Snapshot::list(STEP_COUNT, general),
+      step: 0,
+      set: false,
+    }
+  }
+
+  /// Next y Previous mueven el paso del secuenciador, los demás nada.
+  pub fn advance (&mut self, piston: Piston) {
+    match piston {
+      Piston::Next => self.step = (self.step + 1).min(STEP_COUNT - 1),
+      Piston::Previous => self.step = self.step.saturating_sub(1),
+      _ => {}
+    }
+  }
+
+  /// Lo que recuerda un piston, Next y Previous recuerdan el paso actual.
+  pub fn get (&self, piston: Piston) -> Option<&Snapshot> {
+    match piston {
+      Piston::General(n) => self.general.get(n),
+      Piston::Divisional(m, n) => self.divisional.get(m).and_then(|d| d.get(n)),
+      Piston::Step | Piston::Next | Piston::Previous => self.steps.get(self.step),
+    }
+  }
+
+  pub fn get_mut (&mut self, piston: Piston) -> Option<&mut Snapshot> {
+    match piston {
+      Piston::General(n) => self.general.get_mut(n),
+      Piston::Divisional(m, n) => self.divisional.get_mut(m).and_then(|d| d.get_mut(n)),
+      Piston::Step | Piston::Next | Piston::Previous => self.steps.get_mut(self.step),
+    }
+  }
+
+  fn save_list (writer: &mut Writer, list: &[Snapshot]) {
+    writer.u32(list.len() as u32);
+    for snapshot in list.iter() {
+      if !snapshot.stored { writer.u32(::std::u32::MAX); continue; }
+      writer.u32(snapshot.values.len() as u32);
+      for &(index, value) in snapshot.values.iter() {
+        writer.u32(index as u32);
+        writer.f32(value);
+      }
+    }
+  }
+
+  fn load_list (reader: &mut Reader, list: &mut [Snapshot]) -> Result<(), EndOfData> {
+    let count = reader.u32()? as usize;
+    for i in 0..count {
+      let len = reader.u32()?;
+      let mut snapshot = list.get_mut(i);
+      if let Some(ref mut snapshot) = snapshot {
+        snapshot.clear();
+        snapshot.stored = len != ::std::u32::MAX;
+      }
+      if len == ::std::u32::MAX { continue; }
+
+      for _ in 0..len {
+        let index = reader.u32()? as usize;
+        let value = reader.f32()?;
+        // Las que no caben se ignoran, y los parámetros de más también,
+        // para no pasarse de lo reservado
+        if let Some(ref mut snapshot) = snapshot {
+          if snapshot.values.len() < snapshot.values.capacity() {
+            snapshot.values.push((index, value));
+          }
+        }
+      }
+    }
+    Ok(())
+  }
+
+  pub fn save (&self) -> Vec<u8> {
+    let mut writer = Writer::new();
+    Memory::save_list(&mut writer, &self.general);
+    writer.u32(self.divisional.len() as u32);
+    for list in self.divisional.iter() { Memory::save_list(&mut writer, list); }
+    Memory::save_list(&mut writer, &self.steps);
+    writer.u32(self.step as u32);
+    writer.into_bytes()
+  }
+
+  /// Lee lo que guardó save. Si los datos están rotos la memoria queda
+  /// vacía, nunca a medias.
+  pub fn load (&mut self, data: &[u8]) {
+    if self.read(data).is_err() { self.clear(); }
+  }
+
+  fn clear (&mut self) {
+    let lists = self.general.iter_mut()
+      .chain(self.divisional.iter_mut().flat_map(|list| list.iter_mut()))
+      .chain(self.steps.iter_mut());
+    for snapshot in lists { snapshot.clear(); }
+    self.step = 0;
+  }
+
+  fn read (&mut self, data: &[u8]) -> Result<(), EndOfData> {
+    self.clear();
+    let mut reader = Reader::new(data);
+    Memory::load_list(&mut reader, &mut self.general)?;
+    let manuals = reader.u32()? as usize;
+    for m in 0..manuals {
+      match self.divisional.get_mut(m) {
+        Some(list) => Memory::load_list(&mut reader, list)?,
+        None => Memory::load_list(&mut reader, &mut [])?,
+      }
+    }
+    Memory::load_list(&mut reader, &mut self.steps)?;
+    self.step = (reader.u32()? as usize).min(STEP_COUNT - 1);
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn stored (values: &[(usize, f32)]) -> Snapshot {
+    Snapshot { values: values.to_vec(), stored: true }
+  }
+
+  fn filled () -> Memory {
+    let mut memory = Memory::new(4, 2);
+    memory.general[2] = stored(&[(0, 0.0), (3, 0.5), (7, 1.0)]);
+    memory.divisional[1][3] = stored(&[(40, 1.0), (41, 0.0)]);
+    // Sin parámetros, que no es lo mismo que vacía
+    memory.steps[5] = stored(&[]);
+    memory.steps[63] = stored(&[(2, 0.25)]);
+    memory.step = 5;
+    memory
+  }
+
+  #[test]
+  fn save_load_round_trip () {
+    let memory = filled();
+    let mut loaded = Memory::new(4, 2);
+    loaded.load(&memory.save());
+    assert!(loaded.general == memory.general);
+    assert!(loaded.divisional == memory.divisional);
+    assert!(loaded.steps == memory.steps);
+    assert_eq!(loaded.step, memory.step);
+  }
+
+  #[test]
+  fn load_keeps_reserved_size () {
+    let mut memory = Memory::new(4, 2);
+    memory.general[0] = stored(&[(0, 0.0), (1, 0.0), (2, 0.0), (3, 0.0), (4, 0.0)]);
+    let mut loaded = Memory::new(4, 2);
+    loaded.load(&memory.save());
+    assert_eq!(loaded.general[0].values, &[(0, 0.0), (1, 0.0), (2, 0.0), (3, 0.0)]);
+    assert!(loaded.general.iter().all(|s| s.values.capacity() >= 4));
+  }
+
+  #[test]
+  fn broken_data_loads_empty () {
+    let data = filled().save();
+    let mut loaded = filled();
+    loaded.load(&data[.. data.len() - 1]);
+    assert!(loaded.general.iter().all(|s| !s.stored));
+    assert!(loaded.steps.iter().all(|s| !s.stored));
+    assert_eq!(loaded.step, 0);
+  }
+}
@@ -0,0 +1,160 @@
+use division::{Division, MANUAL_COUNT, MANUAL_NAMES};
+
+/*
+
+Los acopladores hacen que una tecla toque también otras notas: la misma
+tecla una octava abajo o arriba (sub y super), o los registros de otra
+división. Siempre van de un manual, cuyas teclas se presionan, a una
+división, que es la que suena. El "Swell to Great" hace que el Great toque
+los registros del Swell.
+
+Unison Off apaga la división del propio manual, así que con Super y
+Unison Off el manual suena solo una octava arriba.
+
+Los acopladores no se encadenan, un acoplador solo responde a las teclas
+de su manual y no a las notas que tocan otros acopladores. Cuando dos
+teclas tocan la misma nota de una división, la división la cuenta dos
+veces pero la suena una sola, ver Division::note_on.
+
+*/
+
+/// Máximo número de acopladores, cada uno tiene un parámetro.
+pub const COUPLER_SLOTS: usize = 12;
+
+pub struct Coupler {
+  pub name: String,
+  /// El manual cuyas teclas activan el acoplador
+  pub source: usize,
+  /// La división que suena
+  pub destination: usize,
+  /// Transposición en semitonos, -12 es un sub y 12 un super
+  pub shift: i32,
+  /// Si en vez de tocar otra división apaga la del manual
+  pub unison_off: bool,
+  pub on: bool,
+}
+
+impl Coupler {
+  pub fn new (name: String, source: usize, destination: usize, shift: i32) -> Coupler {
+    Coupler {
+      name: name,
+      source: source,
+      destination: destination,
+      shift: shift,
+      unison_off: false,
+      on: false,
+    }
+  }
+
+  pub fn unison_off (name: String, manual: usize) -> Coupler {
+    Coupler { unison_off: true, ..Coupler::new(name, manual, manual, 0) }
+  }
+
+  /// La nota que toca una tecla, si cae dentro del rango midi.
+  pub fn target (&self, note: u8) -> Option<u8> {
+    let note = note as i32 + self.shift;
+    if note >= 0 && note <= 127 { Some(note as u8) } else { None }
+  }
+}
+
+/// Los acopladores de la disposición propia: sub, super y unison off en
+/// cada manual, y los acopladores entre manuales de costumbre.
+pub fn builtin () -> Vec<Coupler> {
+  let mut couplers = Vec::new();
+  for manual in 0..MANUAL_COUNT {
+    let name = MANUAL_NAMES[manual];
+    couplers.push(Coupler::new(format!("{} Sub 16'", name), manual, manual, -12));
+    couplers.push(Coupler::new(format!("{} Super 4'", name), manual, manual, 12));
+    couplers.push(Coupler::unison_off(format!("{} Unison Off", name), manual));
+  }
+  couplers.push(Coupler::new("Swell to Great".to_string(), 0, 1, 0));
+  couplers.push(Coupler::new("Great to Pedal".to_string(), 2, 0, 0));
+  couplers.push(Coupler::new("Swell to Pedal".to_string(), 2, 1, 0));
+  couplers
+}
+
+/// Las teclas presionadas de cada manual, y los acopladores que
+/// deciden qué notas de qué divisiones suenan con ellas.
+pub struct Keyboard {
+  pub couplers: Vec<Coupler>,
+
+  /// La velocidad de cada tecla presionada, 0 si no está presionada
+  keys: [[u8; 128]; MANUAL_COUNT],
+}
+
+impl Keyboard {
+  pub fn new (couplers: Vec<Coupler>) -> Keyboard {
+    Keyboard {
+      couplers: couplers,
+      keys: [[0; 128]; MANUAL_COUNT],
+    }
+  }
+
+  fn unison (&self, manual: usize) -> bool {
+    !self.couplers.iter().any(|c| c.on && c.unison_off && c.source == manual)
+  }
+
+  /// Presiona (vel > 0) o suelta (vel = 0) en las divisiones todas las
+  /// notas que toca una tecla.
+  fn sound (&self, divisions: &mut [Division], manual: usize, note: u8, vel: u8) {
+    let mut play = |division: usize, note: u8| {
+      if vel > 0 { divisions[division].note_on(note, vel); }
+      else { divisions[division].note_off(note); }
+    };
+
+    if self.unison(manual) { play(manual, note); }
+
+    for c in self.couplers.iter() {
+      if !c.on || c.unison_off || c.source != manual { continue; }
+      if let Some(target) = c.target(note) { play(c.destination, target); }
+    }
+  }
+
+  pub fn note_on (&mut self, divisions: &mut [Division], manual: usize, note: u8, vel: u8) {
+    let key = note as usize & 0x7F;
+    // Una tecla que ya está presionada no se vuelve a contar
+    if self.keys[manual][key] > 0 { return; }
+    self.keys[manual][key] = vel.max(1);
+    self.sound(divisions, manual, note, vel.max(1));
+  }
+
+  pub fn note_off (&mut self, divisions: &mut [Division], manual: usize, note: u8) {
+    let key = note as usize & 0x7F;
+    if self.keys[manual][key] == 0 { return; }
+    self.keys[manual][key] = 0;
+    self.sound(divisions, manual, note, 0);
+  }
+
+  /// Activa o desactiva un acoplador. Igual que con los registros, las
+  /// teclas presionadas empiezan o dejan de sonar en su destino.
+  pub fn set_coupler (&mut self, divisions: &mut [Division], index: usize, on: bool) {
+    let (source, unison_before) = match self.couplers.get(index) {
+      Some(c) if c.on != on => (c.source, self.unison(c.source)),
+      _ => return,
+    };
+    self.couplers[index].on = on;
+
+    let c = &self.couplers[index];
+    let unison = self.unison(source);
+    for (key, &vel) in self.keys[source].iter().enumerate() {
+      if vel == 0 { continue; }
+      let note = key as u8;
+
+      let (division, target, start) = if c.unison_off {
+        if unison == unison_before { continue; }
+        (source, Some(note), unison)
+      } else {
+        (c.destination, c.target(note), on)
+      };
+
+      if let Some(target) = target {
+        if start { divisions[division].note_on(target, vel); }
+        else { divisions[division].note_off(target); }
+      }
+    }
+  }
+
+  pub fn coupler_name (&self, index: usize) -> Option<&str> {
+    self.couplers.get(index).map(|c| &c.name[..])
+  }
+}
@@ -0,0 +1,103 @@
+use division::{Division, STOP_SLOTS};
+
+/*
+
+El crescendo (Walze) es un rodillo que el organista mueve con el pie, y
+que va agregando registros uno por uno en un orden fijo, del más suave al
+más fuerte. No cambia la registración del organista, solo le suma
+registros, y al volver atrás los quita en el orden contrario.
+
+El tutti es un botón que pone el órgano completo, que aquí es el
+crescendo hasta el final. Mientras está activo reemplaza la registración,
+y al soltarlo vuelve la que había.
+
+*/
+
+/// Lo que agrega un paso del crescendo.
+pub enum Step {
+  /// Un registro, como (manual, slot)
+  Stop(usize, usize),
+  /// Un drawbar del hammond, con su nivel
+  Wheel(usize, f32),
+}
+
+use self::Step::*;
+
+/// El orden en que el crescendo saca los drawbars, del 16' hacia arriba
+/// pero con el 8' antes que el 5 1/3'.
+const WHEEL_ORDER: [usize; 9] = [0, 2, 1, 3, 4, 5, 6, 7, 8];
+
+pub struct Crescendo {
+  /// Los pasos, del más suave al más fuerte
+  steps: Vec<Vec<Step>>,
+  /// Cuántos pasos del crescendo están activos
+  stage: usize,
+  pub tutti: bool,
+}
+
+impl Crescendo {
+  /// Un crescendo con los registros de las divisiones que se cargaron,
+  /// uno por paso, ordenados por Division::stop_loudness. Los que suenan
+  /// igual quedan en el orden de los manuales. Los drawbars se reparten
+  /// a lo largo del recorrido.
+  pub fn new (divisions: &[Division]) -> Crescendo {
+    let mut stops = Vec::new();
+    for (manual, division) in divisions.iter().enumerate() {
+      for slot in 0 .. division.stop_count().min(STOP_SLOTS) {
+        if let Some(loudness) = division.stop_loudness(slot) {
+          stops.push((loudness, manual, slot));
+        }
+      }
+    }
+    stops.sort_by(|a, b| {
+      let ((class_a, weight_a), (class_b, weight_b)) = (a.0, b.0);
+      class_a.cmp(&class_b).then(
+        weight_a.partial_cmp(&weight_b).unwrap_or(::std::cmp::Ordering::Equal))
+    });
+
+    let mut steps: Vec<Vec<Step>> = stops.into_iter()
+      .map(|(_, manual, slot)| vec![Stop(manual, slot)])
+      .collect();
+    while steps.len() < WHEEL_ORDER.len() { steps.push(Vec::new()); }
+
+    let count = steps.len();
+    for (i, &wheel) in WHEEL_ORDER.iter().enumerate() {
+      steps[i * count / WHEEL_ORDER.len()].push(Wheel(wheel, 1.0));
+    }
+
+    Crescendo { steps: steps, stage: 0, tutti: false }
+  }
+
+  /// Posición del rodillo, 0 no agrega nada y 1 agrega todos los pasos.
+  pub fn set_position (&mut self, value: f32) {
+    self.stage = (value * self.steps.len() as f32).round() as usize;
+  }
+
+  fn steps (&self) -> &[Vec<Step>] {
+    if self.tutti { &self.steps } else { &self.steps[.. self.stage.min(self.steps.len())] }
+  }
+
+  /// Si el crescendo o el tutti activan un registro, index es el del
+  /// registro en todos los manuales.
+  pub fn stop (&self, index: usize) -> bool {
+    let (manual, slot) = (index / STOP_SLOTS, index % STOP_SLOTS);
+    self.steps().iter().any(|stage| stage.iter().any(|step| match *step {
+      Stop(m, s) => m == manual && s == slot,
+      _ => false,
+    }))
+  }
+
+  /// El nivel al que el crescendo o el tutti ponen un drawbar, 0 si no
+  /// lo tocan.
+  pub fn wheel (&self, index: usize) -> f32 {
+    let mut level = 0.0;
+    for stage in self.steps().iter() {
+      for step in stage.iter() {
+        if let Wheel(i, l) = *step {
+          if i == index { level = l; }
+        }
+      }
+    }
+    level
+  }
+}
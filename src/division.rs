@@ -0,0 +1,359 @@
+use arnaudio::voice;
+
+use sample::Sample;
+use pipe::*;
+use additive::Spectrum;
+use wind::{Wind, Tremulant};
+
+/*
+
+Una división es la parte del órgano que toca un teclado (manual), con sus
+propios registros. Cada registro tiene uno o más rangos de tubos, y todos
+los rangos de la división comparten las voces: cada voz tiene un oscilador
+por rango, aunque el registro de ese rango esté apagado.
+
+*/
+
+pub const MANUAL_COUNT: usize = 3;
+
+/// Máximo número de registros por manual, cada uno tiene un parámetro.
+pub const STOP_SLOTS: usize = 8;
+
+/// Máximo número de rangos en una división.
+pub const RANK_SLOTS: usize = 16;
+
+pub const MANUAL_NAMES: [&'static str; MANUAL_COUNT] = ["Great", "Swell", "Pedal"];
+
+/// Separación en estéreo de los tubos al empezar, ver Division::width.
+pub const DEFAULT_WIDTH: f32 = 0.6;
+
+/// Las divisiones que están dentro de una caja expresiva.
+pub const ENCLOSED: [bool; MANUAL_COUNT] = [false, true, false];
+
+pub const GREAT: &'static [&'static StopDef] = &[
+  &PRINCIPAL_8,
+  &OCTAVE_4,
+  &FLUTE_4,
+  &TRUMPET_8,
+  &MIXTURE_IV,
+  &UNDA_MARIS,
+  &VOX_HUMANA_8,
+  &HARMONIC_FLUTE_8,
+];
+
+pub const SWELL: &'static [&'static StopDef] = &[
+  &GEDACKT_8,
+  &NAZARD,
+  &TIERCE,
+  &SEPTIME,
+  &SESQUIALTERA_II,
+  &CORNET_V,
+  &VOIX_CELESTE,
+  &OBOE_8,
+];
+
+pub const PEDAL: &'static [&'static StopDef] = &[
+  &SUBBASS_16,
+  &PRINCIPAL_8,
+  &VIOLA_DA_GAMBA_8,
+  &TRAVERSE_FLUTE_8,
+  &BOURDON_16,
+  &GRAVISSIMA_64,
+];
+
+pub const DISPOSITION: [&'static [&'static StopDef]; MANUAL_COUNT] = [GREAT, SWELL, PEDAL];
+
+/// Registros grabados que no vienen con el plugin. Cada uno se agrega
+/// después de los de DISPOSITION, si hay espacio y si sus grabaciones
+/// están en el disco (ver el readme).
+pub const OPTIONAL: [&'static [&'static StopDef]; MANUAL_COUNT] = [
+  &[],
+  &[],
+  &[&SAMPLED_BASS_16, &HARMONIUM_8],
+];
+
+#[derive(Default)]
+pub struct Voice {
+  pub gain: f32,
+  pub note: u8,
+  pub freq: f32,
+  /// Cuántas teclas tocan esta nota, puede ser más de una por los
+  /// acopladores.
+  pub held: u8,
+  pub oscs: [Osc; RANK_SLOTS],
+}
+
+impl voice::Voice for Voice {
+  // Una tecla presionada ocupa su voz aunque no haya registros activos,
+  // para que los tubos suenen si se activa uno mientras tanto.
+  fn is_active(&self) -> bool {
+    self.held > 0 || self.oscs.iter().any(|osc| osc.is_active())
+  }
+}
+
+/// Un registro dentro de la división, los rangos que le
+/// pertenecen son ranks[first .. first+count]
+struct Stop {
+  name: String,
+  first: usize,
+  count: usize,
+  on: bool,
+}
+
+pub struct Division {
+  sample_rate: f32,
+
+  ranks: Vec<Pipe>,
+  stops: Vec<Stop>,
+
+  voices: voice::Manager<Voice>,
+
+  /// Consumo de viento de los tubos que sonaron en el último sample
+  load: f32,
+
+  pub tremulant: Tremulant,
+
+  /// Qué tanto se separan los tubos en estéreo, 0 es mono
+  pub width: f32,
+}
+
+impl Division {
+  /// La semilla diferencia las desviaciones aleatorias de los tubos de
+  /// esta división de las de las otras.
+  pub fn new (defs: &[&StopDef], seed: u32) -> Division {
+    let mut rank_count = 0;
+    let stops = defs.iter().map(|def| {
+      let ranks = def.ranks.iter().map(|rank| {
+        let rank_seed = seed * RANK_SLOTS as u32 + rank_count;
+        rank_count += 1;
+        Pipe::new(rank, rank_seed)
+      }).collect();
+      (def.name.to_string(), ranks)
+    }).collect();
+    Division::from_stops(stops)
+  }
+
+  /// La división de un manual según DISPOSITION, con los registros
+  /// opcionales que se puedan cargar.
+  pub fn builtin (manual: usize) -> Division {
+    let defs: Vec<&StopDef> = DISPOSITION[manual].iter()
+      .chain(OPTIONAL[manual].iter().filter(|def| def.available()))
+      .cloned()
+      .collect();
+    Division::new(&defs, manual as u32)
+  }
+
+  /// Una división con registros que no vienen de StopDef, como los de
+  /// un órgano importado. Cada registro es su nombre y sus rangos. Los
+  /// registros y rangos que no caben se ignoran.
+  pub fn from_stops (defs: Vec<(String, Vec<Pipe>)>) -> Division {
+    let mut ranks = Vec::new();
+    let mut stops = Vec::new();
+
+    for (name, pipes) in defs.into_iter().take(STOP_SLOTS) {
+      let first = ranks.len();
+      ranks.extend(pipes.into_iter().take(RANK_SLOTS - first));
+      stops.push(Stop { name: name, first: first, count: ranks.len() - first, on: false });
+    }
+
+    Division {
+      sample_rate: 1.0,
+      ranks: ranks,
+      stops: stops,
+      voices: Default::default(),
+      load: 0.0,
+      tremulant: Tremulant::new(),
+      width: DEFAULT_WIDTH,
+    }
+  }
+
+  pub fn set_sample_rate (&mut self, fs: f32) {
+    self.sample_rate = fs;
+    self.tremulant.set_sample_rate(fs);
+
+    for voice in self.voices.all_mut() {
+      for (osc, rank) in voice.oscs.iter_mut().zip(self.ranks.iter()) {
+        rank.init(osc, fs);
+      }
+    }
+  }
+
+  pub fn stop_name (&self, index: usize) -> Option<&str> {
+    self.stops.get(index).map(|stop| &stop.name[..])
+  }
+
+  pub fn stop_count (&self) -> usize { self.stops.len() }
+
+  /// Qué tan fuerte suena un registro, como (clase, peso), para ordenar
+  /// el crescendo. Las clases son los fundamentales y los graves, las
+  /// octavas, las mutaciones, las mixturas y por último las lengüetas.
+  /// None si alguno de sus rangos no debe entrar, ver Pipe::loudness.
+  pub fn stop_loudness (&self, index: usize) -> Option<(u8, f32)> {
+    let stop = match self.stops.get(index) {
+      Some(stop) => stop,
+      None => return None,
+    };
+
+    let (mut harm, mut weight, mut reed) = (0.0_f32, 0.0, false);
+    for rank in self.ranks[stop.first .. stop.first + stop.count].iter() {
+      let (h, w, r) = match rank.loudness() {
+        Some(loudness) => loudness,
+        None => return None,
+      };
+      harm = harm.max(h);
+      weight += w;
+      reed = reed || r;
+    }
+
+    let class = if reed { 4 }
+      else if stop.count > 1 && harm > 2.0 { 3 }
+      else if harm > 2.0 { 2 }
+      else if harm > 1.0 { 1 }
+      else { 0 };
+    Some((class, weight))
+  }
+
+  /// Los rangos que tienen un espectro que se puede editar.
+  pub fn spectrum_ranks (&self) -> Vec<usize> {
+    (0..self.ranks.len()).filter(|&i| self.ranks[i].harmonics().is_some()).collect()
+  }
+
+  /// El nombre del registro al que pertenece un rango.
+  pub fn rank_name (&self, rank: usize) -> Option<&str> {
+    self.stops.iter()
+      .find(|stop| rank >= stop.first && rank < stop.first + stop.count)
+      .map(|stop| &stop.name[..])
+  }
+
+  pub fn spectrum (&self, rank: usize) -> Option<&Spectrum> {
+    self.ranks.get(rank).and_then(|rank| rank.spectrum())
+  }
+
+  pub fn harmonics (&self, rank: usize) -> Option<&[f32]> {
+    self.ranks.get(rank).and_then(|rank| rank.harmonics())
+  }
+
+  pub fn set_harmonic (&mut self, rank: usize, index: usize, value: f32) {
+    if let Some(rank) = self.ranks.get_mut(rank) { rank.set_harmonic(index, value); }
+  }
+
+  /// Recoge lo que terminaron de preparar otros hilos, ver Pipe::poll.
+  pub fn poll (&mut self) {
+    for rank in self.ranks.iter_mut() { rank.poll(); }
+  }
+
+  pub fn set_color (&mut self, value: f32, cache: &mut FormCache) {
+    for rank in self.ranks.iter_mut() { rank.set_color(value, cache); }
+  }
+
+  pub fn update_forms (&mut self, cache: &mut FormCache) {
+    for rank in self.ranks.iter_mut() { rank.update_forms(cache); }
+  }
+
+  pub fn set_noise (&mut self, noise: f32) {
+    for rank in self.ranks.iter_mut() { rank.set_noise(noise); }
+  }
+
+  pub fn set_chiff (&mut self, value: f32) {
+    for rank in self.ranks.iter_mut() { rank.set_chiff(value); }
+  }
+
+  pub fn set_chiff_len (&mut self, value: f32) {
+    for rank in self.ranks.iter_mut() { rank.set_chiff_len(value); }
+  }
+
+  /// Activa o desactiva un registro. Igual que en un órgano real, si hay
+  /// teclas presionadas los tubos del registro empiezan o dejan de sonar.
+  pub fn set_stop (&mut self, index: usize, on: bool) {
+    for rank in self.ranks.iter_mut() { rank.poll(); }
+
+    let fs = self.sample_rate;
+    let stop = match self.stops.get_mut(index) {
+      Some(stop) => stop,
+      None => return
+    };
+    if stop.on == on { return; }
+    stop.on = on;
+
+    let ranks = &self.ranks[stop.first .. stop.first + stop.count];
+    for voice in self.voices.iter_mut() {
+      if voice.held == 0 { continue; }
+      let oscs = &mut voice.oscs[stop.first .. stop.first + stop.count];
+      for (osc, rank) in oscs.iter_mut().zip(ranks.iter()) {
+        if on { rank.note_on(osc, voice.note, voice.freq, fs); }
+        else { osc.release(); }
+      }
+    }
+  }
+
+  /// Si la nota ya está sonando por otra tecla solo se cuenta, para que
+  /// no suene el doble ni vuelva a empezar.
+  pub fn note_on (&mut self, note: u8, vel: u8) {
+    if let Some(voice) = self.voices.get(note) {
+      if voice.held > 0 { voice.held += 1; return; }
+    }
+
+    for rank in self.ranks.iter_mut() { rank.poll(); }
+
+    let fs = self.sample_rate;
+    let freq = 440.0 * 2_f32.powf((note as f32 - 69.0) / 12.0);
+
+    let voice = self.voices.note_on(note);
+    // La voz puede ser robada a otra nota, y esa no debe seguir sonando en
+    // los rangos que esta nota no usa.
+    for osc in voice.oscs.iter_mut() { osc.reset(); }
+    voice.gain = vel as f32 / 256.0;
+    voice.note = note;
+    voice.freq = freq;
+    voice.held = 1;
+
+    for stop in self.stops.iter().filter(|stop| stop.on) {
+      let ranks = &self.ranks[stop.first .. stop.first + stop.count];
+      let oscs = &mut voice.oscs[stop.first .. stop.first + stop.count];
+      for (osc, rank) in oscs.iter_mut().zip(ranks.iter()) {
+        rank.note_on(osc, note, freq, fs);
+      }
+    }
+  }
+
+  pub fn note_off (&mut self, note: u8) {
+    if let Some(voice) = self.voices.note_off(note) {
+      if voice.held == 0 { return; }
+      voice.held -= 1;
+      if voice.held == 0 {
+        for osc in voice.oscs.iter_mut() { osc.release(); }
+      }
+    }
+  }
+
+  /// Consumo de viento de la división, cada tubo consume según su
+  /// volumen, sin contar la escala ni la velocidad de la tecla.
+  pub fn load (&self) -> f32 { self.load }
+
+  pub fn clock (&mut self, wind: &Wind) -> Sample {
+    let wind = &self.tremulant.clock(wind);
+    let mut smpl = Sample::zero();
+    let mut load = 0.0;
+
+    for voice in self.voices.iter_mut() {
+      let mut v_smpl = Sample::zero();
+
+      for (osc, rank) in voice.oscs.iter_mut().zip(self.ranks.iter()) {
+        if osc.is_active() {
+          // Paneo de potencia constante, un tubo suena igual de fuerte en
+          // cualquier posición.
+          let s = rank.clock(osc, wind);
+          let pan = osc.pan * self.width;
+          let (l, r) = (((1.0 - pan) / 2.0).sqrt(), ((1.0 + pan) / 2.0).sqrt());
+          v_smpl = v_smpl + Sample::new(s * l, s * r);
+          load += osc.vol * rank.gain;
+        }
+      }
+
+      smpl = smpl + v_smpl.scale(voice.gain);
+    }
+
+    self.load = load;
+    smpl
+  }
+}
@@ -129,9 +129,9 @@ pub struct Gui {
   controls: Vec<Option<Control>>,
 
   /// Índices de los controles que siempre están visibles
-  main_controls: [usize; 28],
+  main_controls: [usize; 29],
 
-  pipe_tabs: TabGroup,
+  manual_tabs: TabGroup,
 
   //controls: Vec<(usize, Box<gui::widget::Control<Value=f32>>)>,
   //group: gui::widget::Group
@@ -235,6 +235,32 @@ impl Editor for Gui {
       }
     }
 
+    // Un tirador de registro, es un drawbar que solo está adentro o afuera.
+    macro_rules! stop {
+      ($x:expr, $y:expr, $i:expr) => {
+        {controls[$i] = Some(
+          Box::new(gui::widget::Toggle::new(
+            $x, 330 - ($y + 91), 16, 92,
+            win.clone(),
+            gui::widget::SeqPaint::new(
+              drawbar_img.clone(),
+              (0, 0), // Posición
+              (16, 92), // Tamaño
+              gui::widget::SeqDirection::Horizontal,
+              16*8, // Distancia, de la primera a la última sección
+              2, // Cantidad
+            ),
+            {
+              let synth = synth.clone();
+              move |v: f32| {
+                synth.set_param($i, v);
+              }
+            }
+          ))
+        ); $i}
+      }
+    }
+
     let mut base_controls = [
 
         // Tonewheels
@@ -243,6 +269,11 @@ impl Editor for Gui {
         knob!(135, 225, 3),
         knob!(175, 225, 20),
 
+        // Pipe, el color, el chiff y su duración
+        knob!(315, 225, 124),
+        knob!(395, 225, 97),
+        knob!(435, 225, 98),
+
         // Distort
 
         // Vibrato
@@ -254,7 +285,11 @@ impl Editor for Gui {
         knob!(295, 30, 7),
         knob!(335, 30, 8),
         knob!(415, 30, 9),
-        //knob!(375, 30, 10), // Dummy
+        knob!(375, 30, 80), // Coral o trémolo
+
+        // Velocidad de la corneta y del tambor, se mueven solas
+        knob!(295, 70, 87),
+        knob!(335, 70, 88),
 
         // Room
         knob!(475, 150, 14),
@@ -272,43 +307,39 @@ impl Editor for Gui {
         drawbar!(205, 89, 27),
         drawbar!(230, 89, 28),
         drawbar!(255, 89, 29),
-
-        // Wheel Drawbars
-        drawbar!(325, 89, 30),
-        drawbar!(350, 89, 36),
-        drawbar!(375, 89, 42),
-        drawbar!(400, 89, 48),
-        drawbar!(425, 89, 54),
     ];
 
-    macro_rules! pipe_tab {
+    // Los registros de cada manual, ocho por pestaña
+    macro_rules! manual_tab {
       ($i:expr) => {
         Tab::new(
           btn_img.clone(),
           (322+ $i*25, 135), (18, 12),
           (18*$i, 0), (18*$i, 12),
           vec![
-            knob!(315, 225, $i*6 + 31),
-            knob!(355, 225, $i*6 + 32),
-            knob!(435, 225, $i*6 + 34),
-            knob!(475, 225, $i*6 + 35),
+            stop!(317, 89, $i*8 + 30),
+            stop!(333, 89, $i*8 + 31),
+            stop!(349, 89, $i*8 + 32),
+            stop!(365, 89, $i*8 + 33),
+            stop!(381, 89, $i*8 + 34),
+            stop!(397, 89, $i*8 + 35),
+            stop!(413, 89, $i*8 + 36),
+            stop!(429, 89, $i*8 + 37),
           ]
         )
       }
     }
 
-    let mut pipe_tabs = TabGroup::new(win.clone(), vec![
-      pipe_tab!(0),
-      pipe_tab!(1),
-      pipe_tab!(2),
-      pipe_tab!(3),
-      pipe_tab!(4),
+    let mut manual_tabs = TabGroup::new(win.clone(), vec![
+      manual_tab!(0),
+      manual_tab!(1),
+      manual_tab!(2),
     ]);
 
     Gui {
       controls: controls,
       main_controls: base_controls,
-      pipe_tabs: pipe_tabs,
+      manual_tabs: manual_tabs,
       background: background,
     }
 
@@ -340,7 +371,7 @@ impl gui::Component for Gui {
         None => {}
       }
     }
-    self.pipe_tabs.event(ev, &mut self.controls);
+    self.manual_tabs.event(ev, &mut self.controls);
   }
 
   fn paint (&self, canvas: &mut gui::Canvas) {
@@ -351,7 +382,7 @@ impl gui::Component for Gui {
         None => {}
       }
     }
-    self.pipe_tabs.paint(canvas, &self.controls);
+    self.manual_tabs.paint(canvas, &self.controls);
   }
 }
 
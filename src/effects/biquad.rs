@@ -0,0 +1,58 @@
+use helpers::*;
+
+/// Filtro de segundo orden, en forma directa transpuesta II. Los
+/// coeficientes son los del cookbook de Robert Bristow-Johnson.
+#[derive(Default)]
+pub struct Biquad {
+  b0: f32, b1: f32, b2: f32,
+  a1: f32, a2: f32,
+  s1: f32, s2: f32,
+}
+
+impl Biquad {
+  fn set (&mut self, b0: f32, b1: f32, b2: f32, a0: f32, a1: f32, a2: f32) {
+    self.b0 = b0 / a0;
+    self.b1 = b1 / a0;
+    self.b2 = b2 / a0;
+    self.a1 = a1 / a0;
+    self.a2 = a2 / a0;
+  }
+
+  /// El ángulo de una frecuencia, como (seno, coseno).
+  fn omega (freq: f32, sample_rate: f32) -> (f32, f32) {
+    let w = 2.0 * PI * freq / sample_rate;
+    (w.sin(), w.cos())
+  }
+
+  pub fn lowpass (&mut self, freq: f32, q: f32, sample_rate: f32) {
+    let (sin, cos) = Biquad::omega(freq, sample_rate);
+    let alpha = sin / (2.0 * q);
+    let b = (1.0 - cos) / 2.0;
+    self.set(b, 2.0*b, b, 1.0 + alpha, -2.0*cos, 1.0 - alpha);
+  }
+
+  pub fn highpass (&mut self, freq: f32, q: f32, sample_rate: f32) {
+    let (sin, cos) = Biquad::omega(freq, sample_rate);
+    let alpha = sin / (2.0 * q);
+    let b = (1.0 + cos) / 2.0;
+    self.set(b, -2.0*b, b, 1.0 + alpha, -2.0*cos, 1.0 - alpha);
+  }
+
+  /// Sube o baja una banda alrededor de freq, en decibeles.
+  pub fn peak (&mut self, freq: f32, q: f32, db: f32, sample_rate: f32) {
+    let (sin, cos) = Biquad::omega(freq, sample_rate);
+    let alpha = sin / (2.0 * q);
+    let a = 10_f32.powf(db / 40.0);
+    self.set(
+      1.0 + alpha*a, -2.0*cos, 1.0 - alpha*a,
+      1.0 + alpha/a, -2.0*cos, 1.0 - alpha/a,
+    );
+  }
+
+  pub fn run (&mut self, x: f32) -> f32 {
+    let y = self.b0*x + self.s1;
+    self.s1 = self.b1*x - self.a1*y + self.s2;
+    self.s2 = self.b2*x - self.a2*y;
+    y
+  }
+}
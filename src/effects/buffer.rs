@@ -29,6 +29,11 @@ impl<T: Default + Copy> Buffer<T> {
     self.data = vec![Default::default(); size];
   }
 
+  /// Llena el buffer de ceros, sin cambiar su tamaño.
+  pub fn clear (&mut self) {
+    for x in self.data.iter_mut() { *x = Default::default(); }
+  }
+
   pub fn push (&mut self, value: T) {
     self.pos = {
       let p = self.pos+1;
@@ -38,7 +43,8 @@ impl<T: Default + Copy> Buffer<T> {
     self.data[self.pos] = value;
   }
 
-  fn index (&self, i: usize) -> T {
+  /// El valor de hace i samples, 0 es el último que se guardó.
+  pub fn at (&self, i: usize) -> T {
     if i > self.size {
       panic!("Buffer data access at {} out of bounds [data size: {}]", i, self.size)
     }
@@ -60,10 +66,14 @@ impl<T: Default + Copy> Buffer<T> {
       panic!("Buffer access at {}s out of bounds [buffer size: {}s]", s, self.secs)
     };
 
-    self.index((s*self.sample_rate) as usize)
+    self.at((s*self.sample_rate) as usize)
   }
 }
 
+impl<T: Default + Copy> Default for Buffer<T> {
+  fn default () -> Buffer<T> { Buffer::new() }
+}
+
 impl Buffer<f32> {
   pub fn interp (&self, s: f32) -> f32 {
     let si = s * self.sample_rate;
@@ -73,8 +83,8 @@ impl Buffer<f32> {
 
     let t = si - (i as f32);
 
-    let a = self.index(i);
-    let b = self.index(j);
+    let a = self.at(i);
+    let b = self.at(j);
 
     lerp(a, b, t)
   }
@@ -0,0 +1,436 @@
+use std::io::{self, Write};
+use std::fs;
+use std::mem;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use helpers::*;
+use effects::biquad::Biquad;
+use effects::convolver::Convolver;
+use sampled::{self, LoadError};
+use handoff;
+
+/*
+
+El gabinete del parlante, como una respuesta al impulso que se convoluciona
+con la señal (ver convolver.rs). Va después del leslie para darle el color
+de sus parlantes, o lo reemplaza como un gabinete de amplificador fijo.
+
+Las respuestas pueden ser archivos WAV en CABINET_DIR, mono o estéreo. Si
+son estéreo cada canal tiene su respuesta, y si son mono los dos canales
+usan la misma. También hay respuestas de los leslie 122 y 147, que no son
+grabaciones sino filtros: la resonancia de la caja, el hueco del crossover,
+la presencia de la corneta y la caída de los agudos. Los dos modelos tienen
+los mismos parlantes y la misma caja, lo que cambia es el amplificador.
+
+Preparar una respuesta tarda (cambiarle la frecuencia de muestreo,
+normalizarla y transformar sus particiones), así que lo hace otro hilo (ver
+handoff.rs). Mientras tanto sigue sonando la anterior, y cuando llega la
+nueva se pasa de una a otra con un cruce, para que no haya un salto. La
+que se fue vuelve al hilo para borrarse allá.
+
+*/
+
+/// Carpeta de las respuestas de los usuarios, igual que las grabaciones.
+const CABINET_DIR: &'static str = "assets/cabinets";
+
+/// Duración de las respuestas de los modelos, en segundos. Los filtros ya
+/// se apagaron para entonces.
+const MODEL_S: f32 = 0.1;
+
+/// Duración máxima de las respuestas de archivos, el resto se ignora. Un
+/// gabinete se apaga mucho antes, lo que sigue es la sala, y el convolver
+/// multiplica todas las particiones juntas al final de cada bloque: con
+/// 0.2 segundos a 48000 son 150 particiones, y el doble durante un cruce.
+const MAX_FILE_S: f32 = 0.2;
+
+/// Las respuestas se normalizan para que suenen igual a esta frecuencia,
+/// que es donde está el cuerpo del sonido del órgano.
+const NORMALIZE_FREQ: f32 = 1000.0;
+
+/// Duración del cruce entre dos respuestas, en segundos.
+const FADE_S: f32 = 0.05;
+
+/// Puntos del sinc con que se cambia la frecuencia de muestreo de una
+/// respuesta, a cada lado del instante que se interpola.
+const RESAMPLE_TAPS: usize = 16;
+
+/// Frecuencia de corte del sinc, relativa a la más baja de las dos
+/// Nyquist. Un poco menos de 1 para que la ventana tenga dónde caer.
+const RESAMPLE_CUTOFF: f64 = 0.95;
+
+/// Un filtro de un modelo, con frecuencia y Q. Peak también tiene la
+/// ganancia en decibeles.
+enum Band {
+  HighPass(f32, f32),
+  LowPass(f32, f32),
+  Peak(f32, f32, f32),
+}
+
+pub struct ModelDef {
+  pub name: &'static str,
+  bands: &'static [Band],
+}
+
+/// El 122 tiene entrada balanceada y su amplificador es un poco más
+/// brillante.
+pub const LESLIE_122: ModelDef = ModelDef {
+  name: "Leslie 122",
+  bands: &[
+    Band::HighPass(40.0, 0.8),
+    Band::Peak(85.0, 1.4, 3.0),
+    Band::Peak(700.0, 1.5, -2.0),
+    Band::Peak(2800.0, 2.0, 3.5),
+    Band::LowPass(5500.0, 0.8),
+    Band::LowPass(7500.0, 0.6),
+  ],
+};
+
+/// El 147 es el mismo gabinete con entrada para órganos de consola, y
+/// suena más redondo.
+pub const LESLIE_147: ModelDef = ModelDef {
+  name: "Leslie 147",
+  bands: &[
+    Band::HighPass(45.0, 0.7),
+    Band::Peak(110.0, 1.0, 2.5),
+    Band::Peak(700.0, 1.5, -1.5),
+    Band::Peak(2400.0, 1.6, 2.5),
+    Band::LowPass(5000.0, 0.7),
+    Band::LowPass(7000.0, 0.6),
+  ],
+};
+
+pub const MODELS: [&'static ModelDef; 2] = [&LESLIE_122, &LESLIE_147];
+
+enum Source {
+  Model(&'static ModelDef),
+  /// El nombre del archivo y sus canales
+  File(String, sampled::Wav),
+}
+
+impl Source {
+  fn name (&self) -> &str {
+    match *self {
+      Source::Model(def) => def.name,
+      Source::File(ref name, _) => name,
+    }
+  }
+
+  /// La respuesta de cada canal, uno o dos, a la frecuencia de muestreo.
+  fn responses (&self, fs: f32) -> Vec<Vec<f32>> {
+    match *self {
+      Source::Model(def) => vec![model_response(def, fs)],
+      Source::File(_, ref wav) => wav.channels.iter().take(2)
+        .map(|channel| resample(channel, wav.sample_rate, fs))
+        .collect(),
+    }
+  }
+}
+
+/// Un impulso pasado por los filtros del modelo.
+fn model_response (def: &ModelDef, fs: f32) -> Vec<f32> {
+  let mut filters: Vec<Biquad> = def.bands.iter().map(|band| {
+    let mut f = Biquad::default();
+    match *band {
+      Band::HighPass(freq, q) => f.highpass(freq, q, fs),
+      Band::LowPass(freq, q) => f.lowpass(freq, q, fs),
+      Band::Peak(freq, q, db) => f.peak(freq, q, db, fs),
+    }
+    f
+  }).collect();
+
+  (0 .. (MODEL_S*fs) as usize).map(|i| {
+    let x = if i == 0 { 1.0 } else { 0.0 };
+    filters.iter_mut().fold(x, |x, f| f.run(x))
+  }).collect()
+}
+
+/// Cambia la frecuencia de muestreo de una respuesta, interpolando con un
+/// sinc con ventana de Blackman. Al bajarla el corte baja a la nueva
+/// Nyquist, para que lo que queda por encima no vuelva como aliasing, y el
+/// sinc se alarga en la misma proporción para seguir igual de empinado.
+fn resample (data: &[f32], from: f32, to: f32) -> Vec<f32> {
+  use std::f64::consts::PI;
+
+  let data = &data[.. data.len().min((MAX_FILE_S*from) as usize)];
+  let step = from as f64 / to as f64;
+  let ratio = step.max(1.0);
+  let cutoff = RESAMPLE_CUTOFF / ratio;
+  let half = RESAMPLE_TAPS as f64 * ratio;
+
+  let len = (data.len() as f64 / step) as usize;
+  (0..len).map(|i| {
+    let pos = i as f64 * step;
+    let first = (pos - half).ceil().max(0.0) as usize;
+    let last = ((pos + half).floor() as usize).min(data.len() - 1);
+    (first .. last+1).map(|j| {
+      // Distancia del punto al instante que se interpola
+      let x = j as f64 - pos;
+      let sinc = if x == 0.0 { 1.0 } else {
+        let y = PI * x * cutoff;
+        y.sin() / y
+      };
+      let w = (x / half + 1.0) / 2.0;
+      let window = 0.42 - 0.5 * (2.0*PI*w).cos() + 0.08 * (4.0*PI*w).cos();
+      data[j] as f64 * sinc * window * cutoff
+    }).sum::<f64>() as f32
+  }).collect()
+}
+
+/// La ganancia de una respuesta en una frecuencia.
+fn gain_at (response: &[f32], freq: f32, fs: f32) -> f32 {
+  let (mut re, mut im) = (0.0, 0.0);
+  for (i, x) in response.iter().enumerate() {
+    let w = 2.0 * PI * freq * i as f32 / fs;
+    re += x * w.cos();
+    im -= x * w.sin();
+  }
+  (re*re + im*im).sqrt()
+}
+
+/// Los convolvers de los dos canales con una respuesta.
+struct Pair {
+  left: Convolver,
+  right: Convolver,
+}
+
+impl Pair {
+  /// Prepara una respuesta a una frecuencia de muestreo, normalizada.
+  fn new (source: &Source, fs: f32) -> Pair {
+    let mut responses = source.responses(fs);
+    let norm = responses.iter()
+      .map(|r| gain_at(r, NORMALIZE_FREQ, fs))
+      .fold(0.0, f32::max);
+    if norm > 0.0 {
+      for r in responses.iter_mut() {
+        for x in r.iter_mut() { *x /= norm; }
+      }
+    }
+
+    Pair {
+      left: Convolver::with_response(&responses[0]),
+      right: Convolver::with_response(responses.last().unwrap()),
+    }
+  }
+}
+
+/// Sin respuesta la señal pasa igual.
+fn run_pair (pair: &mut Option<Pair>, l: f32, r: f32) -> (f32, f32) {
+  match *pair {
+    Some(ref mut pair) => (pair.left.run(l), pair.right.run(r)),
+    None => (l, r),
+  }
+}
+
+/// Lo que comparten el Cabinet y su hilo.
+struct Shared {
+  /// El número del último pedido, solo sirve su respuesta
+  latest: u32,
+  /// Preparar sources[source] a una frecuencia, para latest
+  build: Option<(usize, f32)>,
+  /// Una respuesta lista con su número de pedido
+  ready: Option<(u32, Pair)>,
+  /// Una respuesta que ya no suena. Se borra en el hilo porque las
+  /// respuestas largas ocupan bastante memoria.
+  old: Option<Pair>,
+}
+
+/// El hilo que prepara las respuestas, termina cuando se borra el Cabinet.
+/// Si se pidieron varias mientras preparaba otra, solo prepara la última.
+fn work (sources: Arc<Vec<Source>>, shared: Arc<Mutex<Shared>>) {
+  while Arc::strong_count(&shared) > 1 {
+    handoff::nap();
+    let (build, old) = {
+      let mut shared = handoff::lock(&shared);
+      let shared = &mut *shared;
+      // Una respuesta que el Cabinet no alcanzó a tomar antes de otro pedido
+      let stale = match shared.ready {
+        Some((request, _)) => request != shared.latest,
+        None => false,
+      };
+      let old = (shared.old.take(), if stale { shared.ready.take() } else { None });
+      (shared.build.take().map(|build| (shared.latest, build)), old)
+    };
+    drop(old);
+
+    if let Some((request, (source, fs))) = build {
+      let pair = Some((request, Pair::new(&sources[source], fs)));
+      let late = {
+        let mut shared = handoff::lock(&shared);
+        if shared.latest == request { mem::replace(&mut shared.ready, pair) } else { pair }
+      };
+      drop(late);
+    }
+  }
+}
+
+pub fn report (name: &str, err: &LoadError) {
+  let _ = writeln!(&mut io::stderr(), "[organ] Cabinet '{}' failed to load: {}", name, err);
+}
+
+/// Las respuestas en CABINET_DIR, en orden alfabético.
+fn load_files () -> Vec<Source> {
+  let entries = match fs::read_dir(Path::new(CABINET_DIR)) {
+    Ok(entries) => entries,
+    Err(_) => return Vec::new(),
+  };
+
+  let mut paths: Vec<_> = entries.filter_map(|entry| entry.ok())
+    .map(|entry| entry.path())
+    .filter(|path| {
+      path.extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase() == "wav")
+        .unwrap_or(false)
+    })
+    .collect();
+  paths.sort();
+
+  paths.into_iter().filter_map(|path| {
+    let name = path.file_stem().map_or(String::new(), |s| s.to_string_lossy().into_owned());
+    match sampled::read_wav_channels(&path) {
+      Ok(wav) => Some(Source::File(name, wav)),
+      Err(err) => { report(&name, &err); None },
+    }
+  }).collect()
+}
+
+pub struct Cabinet {
+  sample_rate: f32,
+
+  /// Los modelos y después los archivos, compartidas con el hilo
+  sources: Arc<Vec<Source>>,
+  /// La respuesta elegida, 0 es ninguna y si no es sources[selected-1]
+  selected: usize,
+
+  /// El número del último pedido al hilo, solo sirve su respuesta
+  request: u32,
+  /// Si lo que suena todavía no es lo elegido
+  changing: bool,
+  /// Si el hilo ya recibió el último pedido
+  sent: bool,
+  shared: Arc<Mutex<Shared>>,
+
+  /// La respuesta que suena, y durante el cruce la que se va. Después
+  /// del cruce previous queda hasta que se pueda devolver al hilo.
+  current: Option<Pair>,
+  previous: Option<Pair>,
+  /// El avance del cruce de 0 a 1, y cuánto avanza por sample
+  fade: f32,
+  fade_step: f32,
+}
+
+impl Cabinet {
+  pub fn new () -> Cabinet {
+    let mut sources: Vec<Source> = MODELS.iter().map(|&def| Source::Model(def)).collect();
+    sources.extend(load_files());
+    let sources = Arc::new(sources);
+
+    let shared = Arc::new(Mutex::new(Shared { latest: 0, build: None, ready: None, old: None }));
+    {
+      let (sources, shared) = (sources.clone(), shared.clone());
+      thread::spawn(move || work(sources, shared));
+    }
+
+    Cabinet {
+      sample_rate: 0.0,
+      sources: sources,
+      selected: 0,
+      request: 0,
+      changing: false,
+      sent: true,
+      shared: shared,
+      current: None,
+      previous: None,
+      fade: 1.0,
+      fade_step: 1.0,
+    }
+  }
+
+  pub fn set_sample_rate (&mut self, fs: f32) {
+    self.sample_rate = fs;
+    self.fade_step = 1.0 / (FADE_S * fs);
+    self.change();
+  }
+
+  /// Los nombres de las respuestas, en el orden en que las recorre la
+  /// perilla después de Off.
+  pub fn names (&self) -> Vec<&str> {
+    self.sources.iter().map(|source| source.name()).collect()
+  }
+
+  /// Elige la respuesta con una perilla. Al principio no hay ninguna, y
+  /// el resto del recorrido se reparte entre todas las respuestas.
+  pub fn select (&mut self, value: f32) {
+    let selected = (value * self.sources.len() as f32).round() as usize;
+    let selected = selected.min(self.sources.len());
+    if selected != self.selected {
+      self.selected = selected;
+      self.change();
+    }
+  }
+
+  /// Pide al hilo la respuesta elegida. Sin respuesta no hay nada que
+  /// pedir, pero igual hay que cambiar.
+  fn change (&mut self) {
+    self.request = self.request.wrapping_add(1);
+    self.changing = true;
+    self.sent = false;
+    self.send();
+  }
+
+  /// Le pasa el pedido al hilo, si no tiene el lock.
+  fn send (&mut self) {
+    if let Some(mut shared) = handoff::try_lock(&self.shared) {
+      shared.latest = self.request;
+      shared.build = if self.selected > 0 && self.sample_rate > 0.0 {
+        Some((self.selected - 1, self.sample_rate))
+      } else { None };
+      self.sent = true;
+    }
+  }
+
+  /// Devuelve al hilo la respuesta que terminó de sonar.
+  fn release (&mut self) {
+    if let Some(mut shared) = handoff::try_lock(&self.shared) {
+      if shared.old.is_none() { shared.old = self.previous.take(); }
+    }
+  }
+
+  /// Empieza el cruce si ya está lo elegido.
+  fn poll (&mut self) {
+    if !self.sent { self.send(); }
+    if !self.sent { return; }
+    let next = if self.selected == 0 || self.sample_rate <= 0.0 { None } else {
+      let mut shared = match handoff::try_lock(&self.shared) {
+        Some(shared) => shared,
+        None => return,
+      };
+      match shared.ready {
+        Some((request, _)) if request == self.request => {},
+        _ => return,
+      }
+      shared.ready.take().map(|(_, pair)| pair)
+    };
+
+    self.changing = false;
+    self.previous = mem::replace(&mut self.current, next);
+    self.fade = 0.0;
+  }
+
+  pub fn run (&mut self, l: f32, r: f32) -> (f32, f32) {
+    if self.fade >= 1.0 {
+      if self.previous.is_some() { self.release(); }
+      // Un cambio espera a que termine el cruce anterior
+      if self.changing && self.previous.is_none() { self.poll(); }
+    }
+
+    let (cl, cr) = run_pair(&mut self.current, l, r);
+    if self.fade >= 1.0 { return (cl, cr); }
+
+    let (pl, pr) = run_pair(&mut self.previous, l, r);
+    let t = self.fade;
+    self.fade += self.fade_step;
+    (lerp(pl, cl, t), lerp(pr, cr, t))
+  }
+}
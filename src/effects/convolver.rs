@@ -0,0 +1,203 @@
+use effects::fft::Fft;
+
+/*
+
+Convolución con una respuesta al impulso larga, particionada. La respuesta
+se corta en bloques de BLOCK samples. El primer bloque (la cabeza) se
+convoluciona directamente sample por sample, así que no agrega latencia.
+Los demás bloques se convolucionan en frecuencia con overlap-save: cada vez
+que se completa un bloque de entrada se transforma junto con el anterior y
+se guarda, y la cola del siguiente bloque de salida es la suma de cada
+partición por la entrada de hace tantos bloques como su posición.
+
+La partición k empieza k bloques después de la entrada, así que su parte
+de la salida se puede calcular al terminar el bloque anterior, sin esperar
+nada. El costo es una FFT y una inversa por bloque, más un producto por
+partición.
+
+*/
+
+/// Samples de cada partición, y de la cabeza directa.
+pub const BLOCK: usize = 64;
+
+/// Un espectro de 2*BLOCK puntos, partes real e imaginaria.
+struct Spectrum {
+  re: Vec<f32>,
+  im: Vec<f32>,
+}
+
+impl Spectrum {
+  fn zero () -> Spectrum {
+    Spectrum { re: vec![0.0; 2*BLOCK], im: vec![0.0; 2*BLOCK] }
+  }
+}
+
+pub struct Convolver {
+  fft: Fft,
+
+  /// Los primeros BLOCK samples de la respuesta
+  head: Vec<f32>,
+  /// Las últimas BLOCK entradas, dos veces seguidas para que la cabeza
+  /// las lea sin dar la vuelta.
+  history: Vec<f32>,
+
+  /// El espectro de cada partición después de la cabeza
+  partitions: Vec<Spectrum>,
+  /// Los espectros de las últimas entradas, uno por partición. El más
+  /// reciente está en recent.
+  inputs: Vec<Spectrum>,
+  recent: usize,
+
+  /// El bloque de entrada anterior y el actual
+  input: Vec<f32>,
+  /// La parte de las particiones en el bloque de salida actual
+  tail: Vec<f32>,
+  /// Posición dentro del bloque actual
+  pos: usize,
+
+  scratch: Spectrum,
+}
+
+impl Convolver {
+  /// Un convolver sin respuesta, que devuelve silencio.
+  pub fn new () -> Convolver {
+    Convolver::with_response(&[])
+  }
+
+  pub fn with_response (response: &[f32]) -> Convolver {
+    let fft = Fft::new(2*BLOCK);
+
+    let mut head = vec![0.0; BLOCK];
+    for (h, x) in head.iter_mut().zip(response.iter()) { *h = *x; }
+
+    let partitions: Vec<Spectrum> = response.chunks(BLOCK).skip(1).map(|part| {
+      let mut spectrum = Spectrum::zero();
+      spectrum.re[..part.len()].copy_from_slice(part);
+      fft.run(&mut spectrum.re, &mut spectrum.im, false);
+      spectrum
+    }).collect();
+
+    Convolver {
+      inputs: partitions.iter().map(|_| Spectrum::zero()).collect(),
+      partitions: partitions,
+      fft: fft,
+      head: head,
+      history: vec![0.0; 2*BLOCK],
+      recent: 0,
+      input: vec![0.0; 2*BLOCK],
+      tail: vec![0.0; BLOCK],
+      pos: 0,
+      scratch: Spectrum::zero(),
+    }
+  }
+
+  pub fn run (&mut self, x: f32) -> f32 {
+    // La entrada más reciente queda en history[pos+BLOCK], y las
+    // anteriores hacia atrás desde ahí.
+    let pos = self.pos;
+    self.history[pos] = x;
+    self.history[pos + BLOCK] = x;
+
+    let recent = &self.history[pos+1 .. pos+BLOCK+1];
+    let head = self.head.iter().zip(recent.iter().rev())
+      .map(|(h, x)| h * x).sum::<f32>();
+    let y = head + self.tail[pos];
+
+    self.input[BLOCK + pos] = x;
+    self.pos += 1;
+    if self.pos == BLOCK {
+      self.pos = 0;
+      self.block();
+    }
+    y
+  }
+
+  /// Calcula la cola del siguiente bloque de salida.
+  fn block (&mut self) {
+    let count = self.partitions.len();
+    if count > 0 {
+      self.recent = (self.recent + count - 1) % count;
+      {
+        let spectrum = &mut self.inputs[self.recent];
+        spectrum.re.copy_from_slice(&self.input);
+        for x in spectrum.im.iter_mut() { *x = 0.0; }
+        self.fft.run(&mut spectrum.re, &mut spectrum.im, false);
+      }
+
+      let acc = &mut self.scratch;
+      for x in acc.re.iter_mut() { *x = 0.0; }
+      for x in acc.im.iter_mut() { *x = 0.0; }
+
+      // La partición k (desde 0, que es la segunda de la respuesta) va
+      // con la entrada de hace k bloques.
+      for (k, h) in self.partitions.iter().enumerate() {
+        let x = &self.inputs[(self.recent + k) % count];
+        for i in 0 .. 2*BLOCK {
+          acc.re[i] += x.re[i]*h.re[i] - x.im[i]*h.im[i];
+          acc.im[i] += x.re[i]*h.im[i] + x.im[i]*h.re[i];
+        }
+      }
+
+      self.fft.run(&mut acc.re, &mut acc.im, true);
+      let scale = 1.0 / (2*BLOCK) as f32;
+      for (t, y) in self.tail.iter_mut().zip(acc.re[BLOCK..].iter()) {
+        *t = y * scale;
+      }
+    }
+
+    let (old, new) = self.input.split_at_mut(BLOCK);
+    old.copy_from_slice(new);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Números entre -1 y 1 que siempre son los mismos.
+  fn noise (len: usize, seed: u32) -> Vec<f32> {
+    let mut x = seed;
+    (0..len).map(|_| {
+      x = x.wrapping_mul(1664525).wrapping_add(1013904223);
+      (x >> 8) as f32 / (1 << 23) as f32 - 1.0
+    }).collect()
+  }
+
+  fn direct (response: &[f32], input: &[f32]) -> Vec<f32> {
+    (0..input.len()).map(|n| {
+      response.iter().take(n+1).enumerate().map(|(k, h)| h * input[n-k]).sum()
+    }).collect()
+  }
+
+  #[test]
+  fn matches_direct_convolution () {
+    // Varios bloques y uno incompleto al final
+    let response = noise(5*BLOCK + 17, 1);
+    let input = noise(12*BLOCK + 5, 2);
+
+    let mut convolver = Convolver::with_response(&response);
+    let output: Vec<f32> = input.iter().map(|&x| convolver.run(x)).collect();
+
+    for (y, expected) in output.iter().zip(direct(&response, &input).iter()) {
+      assert!((y - expected).abs() < 1e-4);
+    }
+  }
+
+  #[test]
+  fn head_has_no_latency () {
+    let response = noise(3*BLOCK, 3);
+    let mut convolver = Convolver::with_response(&response);
+
+    // Un impulso sale como la respuesta desde el primer sample
+    for (n, h) in response.iter().enumerate() {
+      let y = convolver.run(if n == 0 { 1.0 } else { 0.0 });
+      assert!((y - h).abs() < 1e-5);
+    }
+  }
+
+  #[test]
+  fn empty_is_silent () {
+    let mut convolver = Convolver::new();
+    for x in noise(3*BLOCK, 4) { assert_eq!(convolver.run(x), 0.0); }
+  }
+}
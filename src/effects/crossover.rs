@@ -0,0 +1,36 @@
+use effects::biquad::Biquad;
+
+/*
+
+Un crossover Linkwitz-Riley de cuarto orden separa una señal en graves y
+agudos. Cada banda son dos filtros Butterworth de segundo orden en serie, y
+a diferencia de restar un pasabajos de la señal, las dos bandas suman una
+respuesta plana y en fase: juntas son un pasatodo.
+
+*/
+
+const BUTTERWORTH_Q: f32 = ::std::f32::consts::FRAC_1_SQRT_2;
+
+pub struct Crossover {
+  low: [Biquad; 2],
+  high: [Biquad; 2],
+}
+
+impl Crossover {
+  pub fn new () -> Crossover {
+    Crossover { low: Default::default(), high: Default::default() }
+  }
+
+  /// Cambia la frecuencia de corte sin borrar el estado de los filtros.
+  pub fn config (&mut self, freq: f32, sample_rate: f32) {
+    for f in self.low.iter_mut() { f.lowpass(freq, BUTTERWORTH_Q, sample_rate); }
+    for f in self.high.iter_mut() { f.highpass(freq, BUTTERWORTH_Q, sample_rate); }
+  }
+
+  /// Separa un sample en (graves, agudos).
+  pub fn run (&mut self, x: f32) -> (f32, f32) {
+    let low = self.low[0].run(x);
+    let high = self.high[0].run(x);
+    (self.low[1].run(low), self.high[1].run(high))
+  }
+}
@@ -0,0 +1,119 @@
+use sample::Sample;
+use helpers::*;
+use hammond::StateVariable;
+
+/*
+
+El pedal de expresión del Hammond no es un simple control de volumen. Es un
+circuito que, cuando se cierra, atenúa más los medios que los bajos y los
+agudos, y por eso el órgano suena más "gordo" a volumen bajo en vez de solo
+más débil. Aquí lo imito con un filtro pasabanda centrado en los medios: la
+señal completa se multiplica por la ganancia de los bordes, y la diferencia
+entre esa y la de los medios se aplica solo a la banda.
+
+*/
+
+/// Frecuencia central de la banda que el pedal atenúa más.
+const MID_F: f32 = 800.0;
+
+/// Amortiguamiento del filtro, mientras más alto, más ancha es la banda.
+const MID_Q: f32 = 1.4;
+
+/// Atenuación mínima del pedal en decibeles, con el mínimo en 0.
+const MIN_DB: f32 = -40.0;
+
+/// Proporción de la atenuación (en dB) que se aplica a bajos y agudos.
+const EDGE_RATIO: f32 = 0.5;
+
+/// Tiempo que tardan las ganancias en seguir al pedal, en segundos.
+/// Sin esto, los pasos del CC se escuchan como chasquidos.
+const SMOOTH_S: f32 = 0.02;
+
+pub struct Expression {
+  value: f32,
+  min: f32,
+
+  sample_rate: f32,
+  smooth: f32,
+
+  // Ganancias objetivo y actuales de los medios y de los bordes
+  mid_target: f32,
+  edge_target: f32,
+  mid: f32,
+  edge: f32,
+
+  filter: StateVariable,
+  filter_r: StateVariable,
+}
+
+impl Expression {
+  pub fn new () -> Expression {
+    Expression {
+      value: 1.0,
+      min: 0.0,
+
+      sample_rate: 1.0,
+      smooth: 1.0,
+
+      mid_target: 1.0,
+      edge_target: 1.0,
+      mid: 1.0,
+      edge: 1.0,
+
+      filter: Default::default(),
+      filter_r: Default::default(),
+    }
+  }
+
+  pub fn set_sample_rate (&mut self, sample_rate: f32) {
+    self.sample_rate = sample_rate;
+    self.smooth = 1.0 / (SMOOTH_S * sample_rate);
+    self.filter.set_params(MID_Q, MID_F, sample_rate);
+    self.filter_r.set_params(MID_Q, MID_F, sample_rate);
+  }
+
+  /// Posición del pedal, 0 es cerrado y 1 abierto.
+  pub fn set_value (&mut self, value: f32) {
+    self.value = value;
+    self.recalc();
+  }
+
+  /// Nivel mínimo del pedal cerrado, 0 es MIN_DB y 1 es no atenuar nada.
+  pub fn set_min (&mut self, min: f32) {
+    self.min = min;
+    self.recalc();
+  }
+
+  fn recalc (&mut self) {
+    let min_db = lerp(MIN_DB, 0.0, self.min);
+    let db = lerp(min_db, 0.0, self.value);
+    self.mid_target = db2amp(db);
+    self.edge_target = db2amp(db * EDGE_RATIO);
+  }
+
+  fn follow (&mut self) {
+    self.mid += (self.mid_target - self.mid) * self.smooth;
+    self.edge += (self.edge_target - self.edge) * self.smooth;
+  }
+
+  fn apply (&self, orig: f32, band: f32) -> f32 {
+    // La banda tiene ganancia 1/q en el centro, así que hay que
+    // multiplicarla por q para que quede normalizada.
+    let band = band * MID_Q;
+    orig*self.edge + band*(self.mid - self.edge)
+  }
+
+  pub fn run (&mut self, orig: f32) -> f32 {
+    self.follow();
+    let (_, band, _) = self.filter.clock(orig);
+    self.apply(orig, band)
+  }
+
+  pub fn run_stereo (&mut self, orig: Sample) -> Sample {
+    self.follow();
+    let (l, r) = orig.to_tuple();
+    let (_, band_l, _) = self.filter.clock(l);
+    let (_, band_r, _) = self.filter_r.clock(r);
+    Sample::new(self.apply(l, band_l), self.apply(r, band_r))
+  }
+}
@@ -0,0 +1,73 @@
+use std::f64::consts::PI;
+
+/*
+
+Transformada rápida de Fourier compleja, radix 2, en el lugar. Las partes
+real e imaginaria van en arreglos separados. El tamaño tiene que ser una
+potencia de 2, y las tablas de senos y del orden de bits invertido se
+calculan una sola vez al crearla.
+
+*/
+
+pub struct Fft {
+  size: usize,
+  /// El índice con los bits invertidos de cada índice
+  reversed: Vec<usize>,
+  /// cos y sin de -2πk/size, para k < size/2
+  cos: Vec<f32>,
+  sin: Vec<f32>,
+}
+
+impl Fft {
+  pub fn new (size: usize) -> Fft {
+    assert!(size.is_power_of_two());
+    let bits = size.trailing_zeros();
+
+    let reversed = (0..size).map(|i| {
+      (0..bits).fold(0, |r, b| r << 1 | (i >> b & 1))
+    }).collect();
+
+    let angle = |k: usize| -2.0 * PI * k as f64 / size as f64;
+    Fft {
+      size: size,
+      reversed: reversed,
+      cos: (0 .. size/2).map(|k| angle(k).cos() as f32).collect(),
+      sin: (0 .. size/2).map(|k| angle(k).sin() as f32).collect(),
+    }
+  }
+
+  /// La transformada directa. La inversa no divide por el tamaño.
+  pub fn run (&self, re: &mut [f32], im: &mut [f32], inverse: bool) {
+    let n = self.size;
+
+    for i in 0..n {
+      let j = self.reversed[i];
+      if i < j {
+        re.swap(i, j);
+        im.swap(i, j);
+      }
+    }
+
+    let sign = if inverse { -1.0 } else { 1.0 };
+    let mut len = 2;
+    while len <= n {
+      let half = len / 2;
+      let step = n / len;
+      let mut start = 0;
+      while start < n {
+        for k in 0..half {
+          let (wr, wi) = (self.cos[k*step], sign * self.sin[k*step]);
+          let (a, b) = (start + k, start + k + half);
+          let tr = re[b]*wr - im[b]*wi;
+          let ti = re[b]*wi + im[b]*wr;
+          re[b] = re[a] - tr;
+          im[b] = im[a] - ti;
+          re[a] += tr;
+          im[a] += ti;
+        }
+        start += len;
+      }
+      len *= 2;
+    }
+  }
+}
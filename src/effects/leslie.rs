@@ -2,6 +2,7 @@
 use sample::*;
 use helpers::*;
 use effects::buffer::*;
+use effects::crossover::Crossover;
 
 /*
 
@@ -11,6 +12,12 @@ sola. Para cada corneta hay que calcular dos canales por cada micrófono.
 Además propia cabina refleja el sonido de la corneta que apunta hacia
 adentro.
 
+Cada rotor tiene su par de micrófonos, así que la corneta y el tambor se
+pueden abrir en estéreo por separado. Cuando la corneta apunta lejos del
+micrófono suena más débil (modulación de amplitud) y llega más tarde
+(Doppler). Un micrófono lejano escucha menos la modulación y más los
+reflejos de la caja.
+
 */
 
 struct Filter {
@@ -31,21 +38,136 @@ impl Filter {
   }
 }
 
+/// La posición del interruptor del leslie.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Speed {
+  /// Frenado, los rotores se detienen
+  Stop,
+  /// Coral (chorale)
+  Slow,
+  /// Trémolo
+  Fast,
+}
+
+/// Un rotor del leslie. Los rotores tienen inercia, así que no cambian de
+/// velocidad de golpe: la corneta es liviana y acelera en un segundo, y el
+/// tambor de los bajos es pesado y tarda varios. El cambio entre coral y
+/// trémolo es lo que más se reconoce del leslie.
+pub struct Rotor {
+  sample_rate: f32,
+  phase: f32,
+
+  /// Velocidad actual, y la que busca, en Hz
+  speed: f32,
+  target: f32,
+
+  /// Velocidades de coral y trémolo en Hz
+  slow: f32,
+  fast: f32,
+
+  /// Ángulo de cada micrófono respecto al frente, en vueltas
+  pub angle: f32,
+  /// Cuánto baja el volumen cuando el rotor apunta atrás, de 0 a 1
+  pub am: f32,
+  /// Retraso máximo del Doppler, en segundos
+  pub doppler: f32,
+  /// Volumen del rotor en la mezcla
+  pub level: f32,
+
+  /// Coeficientes para acercarse a la velocidad al acelerar y al frenar
+  accel: f32,
+  decel: f32,
+  // Los tiempos de arriba en segundos, para recalcular los coeficientes
+  accel_s: f32,
+  decel_s: f32,
+}
+
+impl Rotor {
+  fn new (slow: f32, fast: f32, accel_s: f32, decel_s: f32, am: f32) -> Rotor {
+    Rotor {
+      sample_rate: 1.0,
+      phase: 0.0,
+      speed: slow,
+      target: slow,
+      slow: slow,
+      fast: fast,
+      angle: 0.0,
+      am: am,
+      doppler: DELAY_S,
+      level: 1.0,
+      accel: 1.0,
+      decel: 1.0,
+      accel_s: accel_s,
+      decel_s: decel_s,
+    }
+  }
+
+  /// Coeficiente para llegar al 95% del cambio en un tiempo dado.
+  fn ramp (time: f32, sample_rate: f32) -> f32 {
+    1.0 - (-3.0 / (time * sample_rate)).exp()
+  }
+
+  fn set_sample_rate (&mut self, sample_rate: f32) {
+    self.sample_rate = sample_rate;
+    self.accel = Rotor::ramp(self.accel_s, sample_rate);
+    self.decel = Rotor::ramp(self.decel_s, sample_rate);
+  }
+
+  /// Tiempo en segundos en que el rotor alcanza la nueva velocidad al
+  /// acelerar.
+  pub fn set_accel (&mut self, time: f32) {
+    self.accel_s = time;
+    self.accel = Rotor::ramp(time, self.sample_rate);
+  }
+
+  /// Igual que set_accel pero al frenar.
+  pub fn set_decel (&mut self, time: f32) {
+    self.decel_s = time;
+    self.decel = Rotor::ramp(time, self.sample_rate);
+  }
+
+  fn set_speed (&mut self, speed: Speed) {
+    self.target = match speed {
+      Speed::Stop => 0.0,
+      Speed::Slow => self.slow,
+      Speed::Fast => self.fast,
+    };
+  }
+
+  /// Velocidad actual en Hz.
+  pub fn speed (&self) -> f32 { self.speed }
+
+  /// Ángulo entre los micrófonos, de 0 (juntos) a 1 (a 90° cada uno).
+  pub fn set_angle (&mut self, value: f32) { self.angle = value * 0.25; }
+
+  /// Profundidad del Doppler, 0.5 es lo que da el tamaño de la caja.
+  pub fn set_doppler (&mut self, value: f32) { self.doppler = value * MAX_DELAY_S; }
+
+  /// Avanza un sample y devuelve la fase del rotor.
+  fn clock (&mut self) -> f32 {
+    let coef = if self.target > self.speed { self.accel } else { self.decel };
+    self.speed += (self.target - self.speed) * coef;
+    self.phase = mod1(self.phase + self.speed / self.sample_rate);
+    self.phase
+  }
+}
+
 pub struct Leslie {
-  pub stereo: f32,
+  /// Distancia de los micrófonos, de 0 (pegados) a 1 (lejos)
+  pub distance: f32,
 
   sample_rate: f32,
   h_buffer: Buffer<f32>,
   l_buffer: Buffer<f32>,
 
-  h_delta: f32,
-  l_delta: f32,
-
-  h_phase: f32,
-  l_phase: f32,
+  speed: Speed,
+  /// La corneta de arriba y el tambor de los bajos
+  pub horn: Rotor,
+  pub drum: Rotor,
 
-  lp1: Filter,
-  lp2: Filter,
+  /// Separa lo que va a la corneta y al tambor
+  split: Crossover,
+  split_f: f32,
 
   damp: Filter,
 }
@@ -61,11 +183,22 @@ const DELAY_S: f32 = SIZE_M / 900.0;
 /// Frecuencia que separa las cornetas de arriba y de abajo.
 const SPLIT_F: f32 = 880.0;
 
-/// Volumen de la corneta cuando está atrás.
-const MIN_VOL: f32 = 0.4;
+// Rango de la frecuencia de separación, en Hz.
+const MIN_SPLIT_F: f32 = 200.0;
+const MAX_SPLIT_F: f32 = 3000.0;
+
+/// El Doppler más profundo, el doble del que da el tamaño de la caja.
+const MAX_DELAY_S: f32 = DELAY_S * 2.0;
+
+/// Cuánto baja el volumen de la corneta cuando está atrás.
+const HORN_AM: f32 = 0.6;
 
-/// Volumen del bajo cuando está atrás.
-const LOW_MIN_VOL: f32 = 0.75;
+/// Cuánto baja el volumen del bajo cuando está atrás.
+const DRUM_AM: f32 = 0.25;
+
+/// La distancia de los micrófonos con la que la modulación y los reflejos
+/// quedan como están arriba.
+const REF_DISTANCE: f32 = 0.5;
 
 /// Cuttoff de los reflejos de la caja.
 const DAMP_F: f32 = 880.0;
@@ -76,47 +209,87 @@ const DAMP_VOL: f32 = 0.3;
 const MIN_FREQ: f32 = 0.1;
 const MAX_FREQ: f32 = 20.0;
 
+// Velocidades de coral y trémolo de un leslie 122, en Hz. El trémolo se
+// puede cambiar con set_h_fast y set_l_fast.
+const HORN_SLOW: f32 = 0.8;
+const HORN_FAST: f32 = 6.7;
+const DRUM_SLOW: f32 = 0.7;
+const DRUM_FAST: f32 = 5.7;
+
+// Tiempos de aceleración y frenado de cada rotor, en segundos.
+const HORN_ACCEL_S: f32 = 1.0;
+const HORN_DECEL_S: f32 = 1.6;
+const DRUM_ACCEL_S: f32 = 5.0;
+const DRUM_DECEL_S: f32 = 4.0;
+
 impl Leslie {
   pub fn new () -> Leslie {
     Leslie {
-      stereo: 0.0,
+      distance: REF_DISTANCE,
 
       sample_rate: 1.0,
       h_buffer: Buffer::new(),
       l_buffer: Buffer::new(),
 
-      lp1: Filter::new(),
-      lp2: Filter::new(),
+      split: Crossover::new(),
+      split_f: SPLIT_F,
 
       damp: Filter::new(),
 
-      h_delta: 0.0,
-      l_delta: 0.0,
-      h_phase: 0.0,
-      l_phase: 0.0,
+      speed: Speed::Slow,
+      horn: Rotor::new(HORN_SLOW, HORN_FAST, HORN_ACCEL_S, HORN_DECEL_S, HORN_AM),
+      drum: Rotor::new(DRUM_SLOW, DRUM_FAST, DRUM_ACCEL_S, DRUM_DECEL_S, DRUM_AM),
     }
   }
 
   pub fn set_sample_rate (&mut self, sample_rate: f32) {
     self.sample_rate = sample_rate;
 
-    self.h_buffer.init(DELAY_S, sample_rate);
-    self.l_buffer.init(DELAY_S, sample_rate);
+    self.h_buffer.init(MAX_DELAY_S, sample_rate);
+    self.l_buffer.init(MAX_DELAY_S, sample_rate);
 
-    self.lp1.config(SPLIT_F, sample_rate);
-    self.lp2.config(SPLIT_F, sample_rate);
+    self.split.config(self.split_f, sample_rate);
 
     self.damp.config(DAMP_F, sample_rate);
+
+    self.horn.set_sample_rate(sample_rate);
+    self.drum.set_sample_rate(sample_rate);
+  }
+
+  pub fn set_speed (&mut self, speed: Speed) {
+    self.speed = speed;
+    self.horn.set_speed(speed);
+    self.drum.set_speed(speed);
   }
 
-  pub fn set_h_freq (&mut self, f: f32) {
-    let freq = lerp(MIN_FREQ, MAX_FREQ, f);
-    self.h_delta = freq / self.sample_rate;
+  /// Velocidad de trémolo de la corneta, el rotor llega a ella con su
+  /// inercia si está en trémolo.
+  pub fn set_h_fast (&mut self, f: f32) {
+    self.horn.fast = lerp(MIN_FREQ, MAX_FREQ, f);
+    self.horn.set_speed(self.speed);
   }
 
-  pub fn set_l_freq (&mut self, f: f32) {
-    let freq = lerp(MIN_FREQ, MAX_FREQ, f);
-    self.l_delta = freq / self.sample_rate;
+  pub fn set_l_fast (&mut self, f: f32) {
+    self.drum.fast = lerp(MIN_FREQ, MAX_FREQ, f);
+    self.drum.set_speed(self.speed);
+  }
+
+  /// Frecuencia de separación entre la corneta y el tambor, de 0 a 1.
+  pub fn set_split (&mut self, value: f32) {
+    self.split_f = MIN_SPLIT_F * (MAX_SPLIT_F / MIN_SPLIT_F).powf(value);
+    self.split.config(self.split_f, self.sample_rate);
+  }
+
+  /// Volumen de la corneta y del tambor. En 0 solo suena el tambor, en 1
+  /// solo la corneta, y en 0.5 los dos completos.
+  pub fn set_balance (&mut self, value: f32) {
+    self.horn.level = (2.0 * value).min(1.0);
+    self.drum.level = (2.0 * (1.0 - value)).min(1.0);
+  }
+
+  /// La velocidad de un rotor en la escala de set_h_fast y set_l_fast.
+  pub fn knob_speed (rotor: &Rotor) -> f32 {
+    ((rotor.speed - MIN_FREQ) / (MAX_FREQ - MIN_FREQ)).max(0.0).min(1.0)
   }
 
   pub fn run (&mut self, orig: f32) -> (f32, f32) {
@@ -126,32 +299,34 @@ impl Leslie {
       ((2.0*PI*x).cos() + 1.0)/2.0
     }
 
-    fn speak (buf: &mut Buffer<f32>, x: f32, min: f32) -> f32 {
+    fn speak (buf: &mut Buffer<f32>, x: f32, am: f32, delay: f32) -> f32 {
       let x = cos01(x);
-      let vol = lerp(min, 1.0, x);
+      let vol = 1.0 - am*(1.0-x);
       let phase = 1.0-x;
-      buf.interp(phase*DELAY_S)*vol
+      buf.interp(phase*delay)*vol
     }
 
-    // Dos filtros en serie para separar mejor las cornetas
-    let tmp = self.lp1.run(orig);
-    let lp = self.lp2.run(tmp);
-    let hp = orig - lp;
+    // Más cerca el micrófono escucha más la modulación, y más lejos más
+    // los reflejos.
+    let near = (1.0 - self.distance) / (1.0 - REF_DISTANCE);
+    let far = self.distance / REF_DISTANCE;
+    let horn_am = (self.horn.am * near).min(1.0);
+    let drum_am = (self.drum.am * near).min(1.0);
+
+    let (lp, hp) = self.split.run(orig);
 
     self.l_buffer.push(lp);
     self.h_buffer.push(hp);
 
-    let h_phase = self.h_phase + self.h_delta;
-    let l_phase = self.l_phase + self.l_delta;
-    self.h_phase = mod1(h_phase);
-    self.l_phase = mod1(l_phase);
-
-    let stereo = self.stereo * 0.25;
+    let h_phase = self.horn.clock();
+    let l_phase = self.drum.clock();
 
     // Cada micrófono escucha un sonido diferente porque la corneta
     // se mueve hacia ellos en momentos diferentes.
-    let left  = speak(&mut self.h_buffer, h_phase - stereo, MIN_VOL);
-    let right = speak(&mut self.h_buffer, h_phase + stereo, MIN_VOL);
+    let angle = self.horn.angle;
+    let doppler = self.horn.doppler;
+    let left  = speak(&mut self.h_buffer, h_phase - angle, horn_am, doppler);
+    let right = speak(&mut self.h_buffer, h_phase + angle, horn_am, doppler);
 
     // Esta señal sale de la mitad de la caja, lo uso para los
     // reflejos de la caja
@@ -159,16 +334,65 @@ impl Leslie {
 
     // El sonido rebota muchas veces dentro de la caja, y resulta
     // en un sonido "mojado".
-    let damp = self.damp.run(delayed) * DAMP_VOL;
+    let damp = self.damp.run(delayed) * DAMP_VOL * far;
 
     // Corneta de los bajos, gira al contrario que la de arriba, y
     // va a una velocidad diferente.
-    let low_l = speak(&mut self.l_buffer, l_phase + stereo, LOW_MIN_VOL);
-    let low_r = speak(&mut self.l_buffer, l_phase - stereo, LOW_MIN_VOL);
+    let angle = self.drum.angle;
+    let doppler = self.drum.doppler;
+    let low_l = speak(&mut self.l_buffer, l_phase + angle, drum_am, doppler);
+    let low_r = speak(&mut self.l_buffer, l_phase - angle, drum_am, doppler);
 
     // Los ecos en el bajo casi no se escuchan así que no los proceso
 
     // Mezcla final del sonido.
-    (left+damp+low_l, right+damp+low_r)
+    let (horn, drum) = (self.horn.level, self.drum.level);
+    ((left+damp)*horn + low_l*drum, (right+damp)*horn + low_r*drum)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn stop (rotor: &mut Rotor) {
+    rotor.speed = 0.0;
+    rotor.target = 0.0;
+    rotor.phase = 0.0;
+  }
+
+  // Con los rotores detenidos apuntando al micrófono y sin reflejos, el
+  // leslie es solo el crossover, y la corneta y el tambor juntos deben
+  // sonar planos.
+  #[test]
+  fn crossover_sum_is_flat () {
+    let fs = 44100.0;
+    for &split in [0.0, 0.55, 1.0].iter() {
+      let mut leslie = Leslie::new();
+      leslie.set_sample_rate(fs);
+      leslie.set_split(split);
+      leslie.distance = 0.0;
+      stop(&mut leslie.horn);
+      stop(&mut leslie.drum);
+
+      let response: Vec<(f32, f32)> = (0..8192)
+        .map(|n| leslie.run(if n == 0 { 1.0 } else { 0.0 }))
+        .collect();
+
+      for i in 0..40 {
+        let freq = 20.0 * 1000_f32.powf(i as f32 / 40.0);
+        let w = 2.0 * PI * freq / fs;
+        let (mut re_l, mut im_l, mut re_r, mut im_r) = (0.0, 0.0, 0.0, 0.0);
+        for (n, &(l, r)) in response.iter().enumerate() {
+          let (sin, cos) = (w * n as f32).sin_cos();
+          re_l += l * cos; im_l -= l * sin;
+          re_r += r * cos; im_r -= r * sin;
+        }
+        for &(re, im) in [(re_l, im_l), (re_r, im_r)].iter() {
+          let db = 10.0 * (re*re + im*im).log10();
+          assert!(db.abs() < 0.1, "split {}: {} dB at {} Hz", split, db, freq);
+        }
+      }
+    }
   }
 }
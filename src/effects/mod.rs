@@ -1,9 +1,16 @@
 
-mod buffer;
+pub mod buffer;
+pub mod biquad;
+pub mod fft;
+pub mod convolver;
+pub mod crossover;
 
 pub mod vibrato;
 pub mod leslie;
 pub mod room;
 pub mod waver;
+pub mod expression;
+pub mod swell;
+pub mod cabinet;
 
 pub use self::waver::Waver;
@@ -1,8 +1,32 @@
-
 use sample::*;
 use effects::buffer::*;
 use helpers::*;
 
+/*
+
+La sala se diseñó contando samples a 44.1kHz, así que los números de abajo
+están en samples a DESIGN_RATE, pero se guardan en segundos. Al cambiar la
+frecuencia de muestreo o el tamaño, set_sample_rate y set_size convierten
+cada tiempo a samples redondeando, para que la respuesta sea la misma a
+cualquier frecuencia y no se corra un sample en cada lectura.
+
+*/
+
+/// La frecuencia de muestreo en que están los números del diseño.
+const DESIGN_RATE: f32 = 44100.0;
+
+/// Cuánto retrasa la difusión a los pulsos, que se adelantan eso.
+const DIFFUSION_LATENCY: f32 = 7200.0 / DESIGN_RATE;
+
+/// Cuánto se adelantan los reflejos tempranos, el retraso del último
+/// allpass (ap4).
+const ER_LATENCY: f32 = 1519.0 / DESIGN_RATE;
+
+/// Samples que equivalen a un tiempo en segundos, redondeado.
+fn to_samples (secs: f32, sample_rate: f32) -> usize {
+  (secs * sample_rate).round() as usize
+}
+
 // Pulse Stuff
   #[derive(Clone, Copy)]
   enum Side {L, R, NL, NR}
@@ -18,8 +42,8 @@ use helpers::*;
       Pulse{
         side: Side::$side,
         gain: $gain,
-        // Restar los samples que la difusión retrasa, y convertirlo a Segundos
-        delay: ( $delay - 7200.0 ) / 44100.0,
+        // Convertirlo a segundos, y restar lo que la difusión retrasa
+        delay: $delay / DESIGN_RATE - DIFFUSION_LATENCY,
       }
     };
     ($side:ident, $delay:expr) => {
@@ -50,7 +74,30 @@ use helpers::*;
     pulse!(R, 24652.0 + 3398.0 + 329.0, 1.0),
   ];
 
-  const MAX_PULSE: f32 = 23798.0/44100.0;
+  const MAX_PULSE: f32 = 23798.0 / DESIGN_RATE;
+
+  /// Un reflejo temprano, con su ganancia en cada canal.
+  struct Reflection {
+    delay: f32,
+    l: f32,
+    r: f32,
+  }
+
+  macro_rules! reflection {
+    ($delay:expr, $l:expr, $r:expr) => {
+      Reflection { delay: $delay / DESIGN_RATE - ER_LATENCY, l: $l, r: $r }
+    }
+  }
+
+  const REFLECTIONS: [Reflection; 4] = [
+    reflection!(2577.0, -0.7, 0.0),
+    reflection!(2771.0, 0.0, -0.7),
+    reflection!(4252.0, 1.0, 0.0),
+    reflection!(4581.0, 0.0, 1.0),
+  ];
+
+  /// El reflejo que se repite en los pulsos, en mono.
+  const MONO_REFLECTION: f32 = DIFFUSION_LATENCY - ER_LATENCY;
 // End Pulse Stuff
 
 // Allpass
@@ -60,6 +107,9 @@ use helpers::*;
     delay: f32,
     feedback: f32,
     size: f32,
+    sample_rate: f32,
+    /// El retraso en samples, de delay por size
+    samples: usize,
     buffer: Buffer<Sample>,
   }
 
@@ -69,19 +119,29 @@ use helpers::*;
         delay: delay,
         feedback: feedback,
         size: 1.0,
+        sample_rate: 0.0,
+        samples: 1,
         buffer: Buffer::new(),
       }
     }
 
-    pub fn set_size (&mut self, size: f32) { self.size = size; }
+    fn recalc (&mut self) {
+      self.samples = to_samples(self.delay * self.size, self.sample_rate).max(1);
+    }
+
+    pub fn set_size (&mut self, size: f32) { self.size = size; self.recalc(); }
     pub fn set_feedback (&mut self, fb: f32) { self.feedback = fb; }
 
     pub fn set_sample_rate (&mut self, sample_rate: f32) {
+      self.sample_rate = sample_rate;
       self.buffer.init(self.delay, sample_rate);
+      self.recalc();
     }
 
     pub fn clock (&mut self, input: Sample) -> Sample {
-      let bufout = self.buffer.get(self.delay * self.size);
+      // Se lee antes de escribir, así que el último sample ya es uno
+      // de retraso
+      let bufout = self.buffer.at(self.samples - 1);
       self.buffer.push(input + bufout.scale(self.feedback));
       bufout - input
     }
@@ -113,7 +173,12 @@ pub struct Room {
 
   gains: [f32; 7],
   rep_gain: f32,
-  rep_delay: f32,
+
+  // Los retrasos en samples, de los tiempos por size
+  reflection_samples: [usize; 4],
+  mono_samples: usize,
+  pulse_samples: [usize; 14],
+  rep_samples: usize,
 }
 
 impl Room {
@@ -150,14 +215,18 @@ impl Room {
         1519: 7*7*31
       */
 
-      ap1: Allpass::new(431.0/44100.0, 0.6),
-      ap2: Allpass::new(571.0/44100.0, 0.6),
-      ap3: Allpass::new(901.0/44100.0, 0.55),
-      ap4: Allpass::new(1519.0/44100.0, 0.5),
+      ap1: Allpass::new(431.0 / DESIGN_RATE, 0.6),
+      ap2: Allpass::new(571.0 / DESIGN_RATE, 0.6),
+      ap3: Allpass::new(901.0 / DESIGN_RATE, 0.55),
+      ap4: Allpass::new(1519.0 / DESIGN_RATE, 0.5),
       
       gains: [0.0; 7],
       rep_gain: 0.0,
-      rep_delay: 0.0,
+
+      reflection_samples: [0; 4],
+      mono_samples: 0,
+      pulse_samples: [0; 14],
+      rep_samples: 1,
     }
   }
 
@@ -167,16 +236,35 @@ impl Room {
     //self.pulse_buf.init(23798.0/44100.0, sample_rate);
     self.pulse_buf.init(MAX_PULSE, sample_rate);
 
-    self.er_buf.init(7200.0/44100.0, sample_rate);
+    self.er_buf.init(DIFFUSION_LATENCY, sample_rate);
 
     self.ap1.set_sample_rate(sample_rate);
     self.ap2.set_sample_rate(sample_rate);
     self.ap3.set_sample_rate(sample_rate);
     self.ap4.set_sample_rate(sample_rate);
 
+    self.recalc_samples();
     self.recalc_delay();
   }
 
+  /// Convierte los tiempos de los reflejos y los pulsos a samples.
+  fn recalc_samples (&mut self) {
+    let fs = self.sample_rate;
+    let size = self.size;
+
+    for (n, r) in self.reflection_samples.iter_mut().zip(REFLECTIONS.iter()) {
+      *n = to_samples(r.delay * size, fs);
+    }
+    let mono = MONO_REFLECTION * size;
+    self.mono_samples = to_samples(mono, fs);
+
+    // Los pulsos ya vienen retrasados por el reflejo mono, así que se
+    // redondea el total para no sumar el error de los dos redondeos.
+    for (n, p) in self.pulse_samples.iter_mut().zip(PULSES.iter()) {
+      *n = to_samples(mono + p.delay * size, fs) - self.mono_samples;
+    }
+  }
+
   pub fn set_size (&mut self, sz: f32) {
     self.size = sz;
 
@@ -185,6 +273,7 @@ impl Room {
     self.ap3.set_size(sz);
     self.ap4.set_size(sz);
 
+    self.recalc_samples();
     self.recalc_delay();
   }
 
@@ -221,15 +310,17 @@ impl Room {
     }
 
     self.rep_gain = rep_gain;
-    self.rep_delay = rep_time;
+    // Se lee antes de escribir, como en los allpass
+    self.rep_samples = to_samples(rep_time, self.sample_rate).max(1);
   }
 
-  fn pulse (&self, pulse: Pulse) -> Sample {
+  fn pulse (&self, index: usize) -> Sample {
 
     //let pulse_gain = 1.0-( pulse.delay / (self.size*23798.0/44100.0) );
     //let vol = self.delay + pulse_gain*(1.0-self.delay);
 
-    let s = self.pulse_buf.get(pulse.delay * self.size);
+    let pulse = PULSES[index];
+    let s = self.pulse_buf.at(self.pulse_samples[index]);
 
     match pulse.side {
       Side::L => Sample::new(s, 0.0),
@@ -243,22 +334,17 @@ impl Room {
     self.er_buf.push( Sample::new(orig_l, orig_r) );
 
     let (er, mono) = {
-      macro_rules! er_pulse {
-        ($x:expr) => { self.er_buf.get(($x - 1519.0) * self.size / 44100.0) };
-        ($x:expr, $l:expr, $r:expr) => { er_pulse!($x).stereo_scale($l, $r) }
+      let mut er = Sample::zero();
+      for (r, &n) in REFLECTIONS.iter().zip(self.reflection_samples.iter()) {
+        er = er + self.er_buf.at(n).stereo_scale(r.l, r.r);
       }
 
-      let a = er_pulse!(2577.0, -0.7, 0.0);
-      let b = er_pulse!(2771.0, 0.0, -0.7);
-      let c = er_pulse!(4252.0, 1.0, 0.0);
-      let d = er_pulse!(4581.0, 0.0, 1.0);
-
-      let mono = er_pulse!(7200.0).get_mono();
+      let mono = self.er_buf.at(self.mono_samples).get_mono();
 
-      (a + b + c + d, mono)
+      (er, mono)
     };
 
-    let repeat_pulse = self.pulse_buf.get(self.rep_delay) * self.rep_gain;
+    let repeat_pulse = self.pulse_buf.at(self.rep_samples - 1) * self.rep_gain;
     self.pulse_buf.push(mono * self.gains[0] + repeat_pulse);
     
     //= Fase de pulsos =/
@@ -267,8 +353,8 @@ impl Room {
       for i in 0 .. 7 {
         let g = self.gains[i];
         s = s +
-          self.pulse(PULSES[i*2]).scale(g) +
-          self.pulse(PULSES[i*2+1]).scale(g);
+          self.pulse(i*2).scale(g) +
+          self.pulse(i*2+1).scale(g);
       }
       s
     };
@@ -286,4 +372,74 @@ impl Room {
 
     Sample::new(orig_l, orig_r).lerp(diffused, self.mix).to_tuple()
   }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn room (sample_rate: f32, diffuse: f32) -> Room {
+    let mut room = Room::new();
+    room.set_sample_rate(sample_rate);
+    room.set_size(0.7);
+    room.set_diffuse(diffuse);
+    room.delay = 0.2;
+    room.recalc_delay();
+    room.mix = 1.0;
+    room
+  }
+
+  /// La respuesta al impulso durante secs segundos, en (l, r).
+  fn response (room: &mut Room, secs: f32) -> Vec<(f32, f32)> {
+    (0 .. (secs * room.sample_rate) as usize)
+      .map(|n| { let x = if n == 0 { 1.0 } else { 0.0 }; room.clock(x, x) })
+      .collect()
+  }
+
+  const RATES: [f32; 2] = [48000.0, 96000.0];
+
+  // Sin difusión la respuesta son pulsos sueltos, que deben llegar al
+  // mismo tiempo y con la misma ganancia a cualquier frecuencia, con
+  // menos de un sample a 44.1kHz de diferencia.
+  #[test]
+  fn pulses_match_across_rates () {
+    let pulses = |fs: f32| -> Vec<(f32, f32, f32)> {
+      response(&mut room(fs, 0.0), 0.8).into_iter().enumerate()
+        .filter(|&(_, (l, r))| l != 0.0 || r != 0.0)
+        .map(|(n, (l, r))| (n as f32 / fs, l, r))
+        .collect()
+    };
+
+    let base = pulses(DESIGN_RATE);
+    assert!(base.len() > 20);
+    for &fs in RATES.iter() {
+      let other = pulses(fs);
+      assert_eq!(base.len(), other.len(), "{} Hz", fs);
+      for (a, b) in base.iter().zip(other.iter()) {
+        assert!((a.0 - b.0).abs() < 1.0 / DESIGN_RATE, "{} Hz: pulse at {}s moved to {}s", fs, a.0, b.0);
+        assert!((a.1 - b.1).abs() < 1e-4 && (a.2 - b.2).abs() < 1e-4, "{} Hz: pulse at {}s", fs, a.0);
+      }
+    }
+  }
+
+  // Con difusión los pulsos se mezclan, pero la energía en cada ventana
+  // de 50ms debe ser la misma.
+  #[test]
+  fn energy_matches_across_rates () {
+    let energy = |fs: f32| -> Vec<f32> {
+      let window = (0.05 * fs) as usize;
+      response(&mut room(fs, 1.0), 1.5).chunks(window)
+        .map(|w| w.iter().map(|&(l, r)| l*l + r*r).sum())
+        .collect()
+    };
+
+    let base = energy(DESIGN_RATE);
+    let peak = base.iter().cloned().fold(0.0, f32::max);
+    for &fs in RATES.iter() {
+      for (i, (a, b)) in base.iter().zip(energy(fs).iter()).enumerate() {
+        if *a < peak * 1e-6 { continue; }
+        let db = 10.0 * (b / a).log10();
+        assert!(db.abs() < 0.5, "{} Hz: {} dB at {}ms", fs, db, i*50);
+      }
+    }
+  }
+}
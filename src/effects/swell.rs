@@ -0,0 +1,116 @@
+use sample::Sample;
+use helpers::*;
+use hammond::StateVariable;
+
+/*
+
+Una caja expresiva (swell box) es una caja de madera alrededor de los tubos
+de una división, con persianas que el organista abre y cierra con un
+pedal. Cerrar las persianas no baja el volumen parejo: la madera deja pasar
+los graves mucho mejor que los agudos, así que la división se oscurece
+antes de sonar más débil. Además la caja tiene su propia resonancia, que
+se nota más mientras más cerrada está.
+
+Aquí es un pasabajos de dos polos que baja con el pedal, una ganancia en
+decibeles, y un pasabanda en la resonancia de la caja que se suma cuando
+se cierra.
+
+*/
+
+// Corte del pasabajos con la caja abierta y cerrada, en Hz. Con la caja
+// abierta el filtro casi no hace nada.
+const OPEN_F: f32 = 16000.0;
+const CLOSED_F: f32 = 700.0;
+
+/// Nivel con la caja cerrada, en decibeles.
+const CLOSED_DB: f32 = -18.0;
+
+// La resonancia de la caja, y cuánto se suma con la caja cerrada.
+const BOX_F: f32 = 220.0;
+const BOX_Q: f32 = 0.3;
+const BOX_MIX: f32 = 0.12;
+
+/// Tiempo que tarda la caja en seguir al pedal, en segundos. Las
+/// persianas no se mueven de golpe.
+const SMOOTH_S: f32 = 0.05;
+
+pub struct SwellBox {
+  sample_rate: f32,
+  smooth: f32,
+
+  /// Posición del pedal, y la de las persianas que la siguen
+  target: f32,
+  position: f32,
+
+  /// Coeficiente de los polos del pasabajos
+  lowpass: f32,
+  /// Estado de los dos polos de cada canal
+  low_l: (f32, f32),
+  low_r: (f32, f32),
+
+  box_l: StateVariable,
+  box_r: StateVariable,
+}
+
+impl SwellBox {
+  pub fn new () -> SwellBox {
+    SwellBox {
+      sample_rate: 44100.0,
+      smooth: 1.0,
+
+      target: 1.0,
+      position: 1.0,
+
+      lowpass: 1.0,
+      low_l: (0.0, 0.0),
+      low_r: (0.0, 0.0),
+      box_l: Default::default(),
+      box_r: Default::default(),
+    }
+  }
+
+  pub fn set_sample_rate (&mut self, sample_rate: f32) {
+    self.sample_rate = sample_rate;
+    self.smooth = 1.0 / (SMOOTH_S * sample_rate);
+    self.box_l.set_params(BOX_Q, BOX_F, sample_rate);
+    self.box_r.set_params(BOX_Q, BOX_F, sample_rate);
+    self.recalc();
+  }
+
+  /// Posición del pedal, 0 es cerrado y 1 abierto.
+  pub fn set_value (&mut self, value: f32) { self.target = value; }
+
+  fn recalc (&mut self) {
+    let f = CLOSED_F * (OPEN_F / CLOSED_F).powf(self.position);
+    self.lowpass = 1.0 - (-2.0 * PI * f / self.sample_rate).exp();
+  }
+
+  /// Dos polos simples en serie, siempre estables aunque el corte pase
+  /// de Nyquist.
+  fn two_poles (a: f32, state: &mut (f32, f32), x: f32) -> f32 {
+    state.0 += (x - state.0) * a;
+    state.1 += (state.0 - state.1) * a;
+    state.1
+  }
+
+  pub fn run (&mut self, orig: Sample) -> Sample {
+    if self.position != self.target {
+      self.position += (self.target - self.position) * self.smooth;
+      if (self.target - self.position).abs() < 0.0001 { self.position = self.target; }
+      self.recalc();
+    }
+
+    let closed = 1.0 - self.position;
+    let gain = db2amp(CLOSED_DB * closed);
+    // La banda tiene ganancia 1/q en el centro
+    let box_mix = BOX_MIX * closed * BOX_Q;
+
+    let (l, r) = orig.to_tuple();
+    let low_l = SwellBox::two_poles(self.lowpass, &mut self.low_l, l);
+    let low_r = SwellBox::two_poles(self.lowpass, &mut self.low_r, r);
+    let (_, band_l, _) = self.box_l.clock(low_l);
+    let (_, band_r, _) = self.box_r.clock(low_r);
+
+    Sample::new(low_l + band_l * box_mix, low_r + band_r * box_mix).scale(gain)
+  }
+}
@@ -0,0 +1,141 @@
+use helpers::*;
+use effects::buffer::Buffer;
+use pipe::Osc;
+use wind::Wind;
+
+/*
+
+Modelo físico de un tubo labial, basado en el flute de Perry Cook (STK).
+
+El aire que entra por la boca del tubo forma un chorro (jet) que tarda un
+poco en cruzar la boca, y que oscila entre afuera y adentro del tubo. El
+tubo (bore) es un resonador, un delay que refleja la onda en sus extremos.
+El chorro se ve afectado por la onda que vuelve del tubo, y eso es lo que
+mantiene la oscilación. La no linealidad del chorro es x³ - x.
+
+El tubo se modela con un solo delay que da la vuelta completa. Un tubo
+abierto refleja la onda invertida en ambos extremos, así que la onda vuelve
+igual y el loop resuena en toda la serie armónica. Un tubo tapado la refleja
+invertida solo en un extremo, la onda vuelve invertida, el loop resuena
+solo en los armónicos impares y la nota suena una octava abajo del largo
+del loop, igual que un tubo tapado real.
+
+*/
+
+/// Período más largo que puede tener el tubo, en segundos. Alcanza para
+/// un tubo abierto de 32'.
+const MAX_BORE_S: f32 = 1.0 / 16.0;
+
+/// Largo de los buffers, con margen para que el viento alargue el tubo.
+const BUFFER_S: f32 = MAX_BORE_S * 1.25;
+
+// Coeficientes de reflexión del chorro y del final del tubo, de STK.
+const JET_REFLECTION: f32 = 0.5;
+const END_REFLECTION: f32 = 0.5;
+
+/// Corrección de la afinación, en samples, por el retraso del filtro.
+const TUNING_SAMPLES: f32 = 1.5;
+
+const OUT_GAIN: f32 = 0.6;
+
+pub struct FlueDef {
+  /// Tubo tapado, solo armónicos impares
+  pub stopped: bool,
+  /// Período del loop respecto al de la nota. Para un tubo tapado es 0.5,
+  /// para un tubo que suena en su segundo modo (overblowing) es 2.
+  pub length: f32,
+  /// Presión del viento. Alrededor de 1 habla normalmente, mucho más alta
+  /// el tubo salta a otro modo o se ahoga.
+  pub pressure: f32,
+  /// Largo del chorro respecto al loop. El tubo suena en el modo cuyo
+  /// período es cerca del doble del chorro, así que más corto sube de modo.
+  pub jet_ratio: f32,
+  /// Ruido del soplo, respecto a la presión
+  pub noise: f32,
+  /// Coeficiente del pasabajos dentro del tubo, más alto es más opaco
+  pub damp: f32,
+}
+
+pub const OPEN: FlueDef = FlueDef {
+  stopped: false,
+  length: 1.0,
+  pressure: 1.0,
+  jet_ratio: 0.5,
+  noise: 0.15,
+  damp: 0.6,
+};
+
+// La flauta armónica tiene el tubo del doble de largo, con el chorro corto
+// y soplado fuerte, y suena una octava arriba.
+pub const HARMONIC: FlueDef = FlueDef {
+  stopped: false,
+  length: 2.0,
+  pressure: 1.2,
+  jet_ratio: 0.25,
+  noise: 0.1,
+  damp: 0.6,
+};
+
+pub const STOPPED: FlueDef = FlueDef {
+  stopped: true,
+  length: 0.5,
+  pressure: 1.0,
+  jet_ratio: 1.0,
+  noise: 0.12,
+  damp: 0.6,
+};
+
+fn jet (x: f32) -> f32 {
+  let y = x * (x*x - 1.0);
+  if y > 1.0 { 1.0 } else if y < -1.0 { -1.0 } else { y }
+}
+
+/// Crea los buffers del tubo. Se llama al cambiar la frecuencia de
+/// muestreo, no al tocar una nota, para no reservar memoria en el hilo
+/// de audio.
+pub fn init (osc: &mut Osc, fs: f32) {
+  osc.bore.init(BUFFER_S, fs);
+  osc.jet.init(BUFFER_S, fs);
+}
+
+pub fn note_on (def: &FlueDef, osc: &mut Osc, freq: f32, fs: f32) {
+  // Una voz reusada tiene todavía la onda de la nota anterior
+  osc.bore.clear();
+  osc.jet.clear();
+
+  let period = def.length / freq;
+  let bore = (period - TUNING_SAMPLES/fs).max(1.0/fs).min(MAX_BORE_S);
+  osc.bore_s = bore;
+  osc.jet_s = bore * def.jet_ratio;
+  osc.loop_lp = 0.0;
+  osc.dc_x = 0.0;
+  osc.dc_y = 0.0;
+}
+
+/// La presión sigue al envelope del tubo (osc.vol) y al viento. El tubo ya
+/// responde solo a la presión, pero el tono casi no cambia con ella, así que
+/// también se acorta el tubo con wind.pitch.
+pub fn clock (def: &FlueDef, osc: &mut Osc, noise: f32, wind: &Wind) -> f32 {
+  let pressure = def.pressure * osc.vol * wind.pressure;
+  let breath = pressure * (1.0 + noise * def.noise);
+
+  let bore_s = osc.bore_s / wind.pitch;
+  osc.loop_lp = lerp(osc.bore.interp(bore_s), osc.loop_lp, def.damp);
+
+  // Bloqueador de DC dentro del loop, si no la presión constante del
+  // soplo se acumula en el tubo y satura el chorro. También sirve
+  // de salida, que así no tiene DC.
+  let x = osc.loop_lp;
+  let temp = x - osc.dc_x + 0.995 * osc.dc_y;
+  osc.dc_x = x;
+  osc.dc_y = temp;
+
+  let reflected = if def.stopped { -temp } else { temp };
+
+  osc.jet.push(breath - JET_REFLECTION * reflected);
+  let jet_out = jet(osc.jet.interp(osc.jet_s / wind.pitch));
+
+  osc.bore.push(jet_out + END_REFLECTION * reflected);
+
+  temp * OUT_GAIN
+}
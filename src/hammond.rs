@@ -39,6 +39,11 @@ impl StateVariable {
     self.q = q;
     self.f = 2.0*(PI*f/fs).sin();
   }
+  /// Borra el estado, sin cambiar los parámetros.
+  pub fn reset (&mut self) {
+    self.l = 0.0;
+    self.b = 0.0;
+  }
   // Devuelve un trío con (lowpass, bandpass, highpass)
   pub fn clock (&mut self, s: f32) -> (f32, f32, f32) {
     let h = s-self.l-self.b*self.q;
@@ -0,0 +1,46 @@
+use std::sync::{Mutex, MutexGuard, TryLockError};
+use std::thread;
+use std::time::Duration;
+
+/*
+
+Cómo se pasan cosas entre el hilo de audio y los hilos que preparan tablas
+o respuestas. El hilo de audio no puede esperar ni reservar o liberar
+memoria, así que no usa canales: std::sync::mpsc reserva en cada mensaje.
+
+En cambio lo compartido tiene un lugar fijo bajo un Mutex, y los dos lados
+lo toman solo con try_lock. Si lo tiene el otro, el hilo de audio vuelve a
+intentar más tarde. Como nadie se queda esperando el lock, soltarlo nunca
+tiene que despertar a otro hilo.
+
+Los hilos de trabajo revisan lo compartido cada POLL_MS, y son los que
+borran lo que el hilo de audio ya no usa. Terminan cuando son los únicos
+que lo tienen.
+
+*/
+
+/// Cada cuánto revisan los hilos de trabajo, en milisegundos.
+const POLL_MS: u64 = 5;
+
+/// Espera entre una revisión y otra de un hilo de trabajo.
+pub fn nap () {
+  thread::sleep(Duration::from_millis(POLL_MS));
+}
+
+/// Toma el lock si está libre, para el hilo de audio. Si un hilo de
+/// trabajo entró en pánico lo que dejó sigue sirviendo.
+pub fn try_lock<T> (mutex: &Mutex<T>) -> Option<MutexGuard<T>> {
+  match mutex.try_lock() {
+    Ok(guard) => Some(guard),
+    Err(TryLockError::Poisoned(err)) => Some(err.into_inner()),
+    Err(TryLockError::WouldBlock) => None,
+  }
+}
+
+/// Toma el lock desde un hilo de trabajo, sin quedarse esperando en él.
+pub fn lock<T> (mutex: &Mutex<T>) -> MutexGuard<T> {
+  loop {
+    if let Some(guard) = try_lock(mutex) { return guard; }
+    nap();
+  }
+}
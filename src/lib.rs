@@ -6,10 +6,23 @@ extern crate arnaudio;
 
 mod sample;
 mod pipe;
+mod reed;
+mod flue;
+mod sampled;
+mod additive;
+mod wavetable;
+mod sfz;
+mod odf;
+mod division;
+mod coupler;
+mod crescendo;
+mod combination;
+mod wind;
 mod hammond;
 mod organ;
 mod effects;
 mod helpers;
+mod handoff;
 mod editor;
 
 synth_main!(organ::Organ);
\ No newline at end of file
@@ -0,0 +1,418 @@
+use std::io::{self, Read, Write};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::fmt;
+
+use pipe::{Pipe, RankDef, Kind, Layout};
+use sampled::{self, PipeFiles, Source};
+use division::{Division, MANUAL_COUNT, STOP_SLOTS, RANK_SLOTS, MANUAL_NAMES};
+use coupler::{Coupler, COUPLER_SLOTS};
+
+/*
+
+Importa órganos en el formato de GrandOrgue (archivos .organ, llamados
+ODF). Es un INI con una sección por cada objeto: [Organ], [Manual001],
+[Stop001], [Rank001], [Coupler001], etc., que se refieren unos a otros por
+número. Las rutas de los samples son relativas al archivo .organ y usan
+backslash.
+
+Solo se lee la parte común del formato: manuales, registros, rangos,
+tubos con un ataque y un release, y acopladores. Los registros viejos
+tienen los tubos directamente (PipeNNN en el registro), y los nuevos se
+refieren a secciones [RankNNN]. Todo lo demás (trémolos, secretos,
+paneles, varios ataques) se ignora.
+
+GrandOrgue numera las teclas desde 1 en cada manual, la nota midi de cada
+tecla sale de FirstAccessibleKeyMIDINoteNumber del manual.
+
+*/
+
+/// Dónde se busca un órgano para cargar en vez de la disposición propia.
+pub const ODF_PATH: &'static str = "assets/organ.organ";
+
+/// Nota midi de la primera tecla, si el manual no la dice.
+const DEFAULT_FIRST_MIDI: i32 = 36;
+
+/// Un acoplador de un manual. Los manuales son los números del ODF.
+pub struct OdfCoupler {
+  pub name: String,
+  pub destination: usize,
+  /// Transposición en semitonos, -12 es un acoplador de 16'
+  pub keyshift: i32,
+  /// Si apaga los registros del propio manual
+  pub unison_off: bool,
+}
+
+pub struct OdfRank {
+  pub name: String,
+  pub gain: f32,
+  pub pipes: Vec<PipeFiles>,
+}
+
+pub struct OdfStop {
+  pub name: String,
+  pub ranks: Vec<OdfRank>,
+}
+
+pub struct OdfManual {
+  pub number: usize,
+  pub name: String,
+  pub stops: Vec<OdfStop>,
+  pub couplers: Vec<OdfCoupler>,
+}
+
+pub struct OdfOrgan {
+  pub name: String,
+  pub manuals: Vec<OdfManual>,
+}
+
+#[derive(Debug)]
+pub enum OdfError {
+  Io(PathBuf, io::Error),
+  /// Falta una sección o una llave obligatoria
+  Missing(String),
+  /// Un valor que no se puede leer
+  Invalid(String, String),
+}
+
+impl fmt::Display for OdfError {
+  fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      OdfError::Io(ref path, ref err) => write!(f, "{}: {}", path.display(), err),
+      OdfError::Missing(ref what) => write!(f, "missing {}", what),
+      OdfError::Invalid(ref what, ref value) => write!(f, "invalid value for {}: '{}'", what, value),
+    }
+  }
+}
+
+/// Las advertencias no detienen la carga, solo se reportan.
+fn warn (msg: String) {
+  let _ = writeln!(&mut io::stderr(), "[organ] ODF: {}", msg);
+}
+
+/// Un INI con secciones y llaves sin distinguir mayúsculas.
+struct Ini {
+  sections: HashMap<String, HashMap<String, String>>,
+}
+
+impl Ini {
+  fn parse (text: &str) -> Ini {
+    let mut sections = HashMap::new();
+    let mut current = String::new();
+
+    for line in text.lines() {
+      let line = line.trim();
+      if line.is_empty() || line.starts_with(';') { continue; }
+
+      if line.starts_with('[') && line.ends_with(']') {
+        current = line[1 .. line.len()-1].trim().to_lowercase();
+        sections.entry(current.clone()).or_insert_with(HashMap::new);
+        continue;
+      }
+
+      if let Some(i) = line.find('=') {
+        let key = line[..i].trim().to_lowercase();
+        let value = line[i+1..].trim().to_string();
+        sections.entry(current.clone())
+          .or_insert_with(HashMap::new)
+          .insert(key, value);
+      }
+    }
+
+    Ini { sections: sections }
+  }
+
+  fn section (&self, name: &str) -> Result<Section, OdfError> {
+    match self.sections.get(&name.to_lowercase()) {
+      Some(keys) => Ok(Section { name: name.to_string(), keys: keys }),
+      None => Err(OdfError::Missing(format!("section [{}]", name))),
+    }
+  }
+}
+
+struct Section<'a> {
+  name: String,
+  keys: &'a HashMap<String, String>,
+}
+
+impl<'a> Section<'a> {
+  fn get (&self, key: &str) -> Option<&'a str> {
+    self.keys.get(&key.to_lowercase()).map(|v| &v[..])
+  }
+
+  fn string (&self, key: &str) -> Result<String, OdfError> {
+    self.get(key)
+      .map(|v| v.to_string())
+      .ok_or_else(|| OdfError::Missing(format!("{} in [{}]", key, self.name)))
+  }
+
+  fn number<T: ::std::str::FromStr> (&self, key: &str) -> Result<Option<T>, OdfError> {
+    match self.get(key) {
+      Some(value) => value.parse().map(Some)
+        .map_err(|_| OdfError::Invalid(format!("{} in [{}]", key, self.name), value.to_string())),
+      None => Ok(None),
+    }
+  }
+
+  fn number_or<T: ::std::str::FromStr> (&self, key: &str, default: T) -> Result<T, OdfError> {
+    self.number(key).map(|v| v.unwrap_or(default))
+  }
+
+  fn flag (&self, key: &str) -> bool {
+    match self.get(key) {
+      Some(v) => v.eq_ignore_ascii_case("y"),
+      None => false,
+    }
+  }
+
+  /// Las referencias a otras secciones, como Stop001=003 o Rank002=010.
+  fn refs (&self, count_key: &str, prefix: &str) -> Result<Vec<usize>, OdfError> {
+    let count = self.number_or(count_key, 0)?;
+    (1..count+1).map(|i| {
+      let key = format!("{}{:03}", prefix, i);
+      self.number(&key)?.ok_or_else(|| OdfError::Missing(format!("{} in [{}]", key, self.name)))
+    }).collect()
+  }
+}
+
+/// Ganancia de un rango o registro. AmplitudeLevel es un porcentaje y
+/// Gain está en decibeles.
+fn gain (section: &Section) -> Result<f32, OdfError> {
+  let level: f32 = section.number_or("AmplitudeLevel", 100.0)?;
+  let db: f32 = section.number_or("Gain", 0.0)?;
+  Ok(level / 100.0 * 10_f32.powf(db / 20.0))
+}
+
+/// Los archivos de un tubo, o None si el tubo no tiene sample propio.
+fn pipe_files (rank: &Section, n: usize, base: &Path, key: u8) -> Option<PipeFiles> {
+  let prefix = format!("Pipe{:03}", n);
+  let path = match rank.get(&prefix) {
+    Some(path) => path,
+    None => return None,
+  };
+
+  // DUMMY es un tubo que no suena, y REF: reusa un tubo de otro rango,
+  // que todavía no se soporta.
+  if path.eq_ignore_ascii_case("DUMMY") { return None; }
+  if path.starts_with("REF:") {
+    warn(format!("[{}] {}: pipe references are not supported", rank.name, prefix));
+    return None;
+  }
+
+  let to_path = |p: &str| base.join(p.replace('\\', "/"));
+  let release = rank.get(&format!("{}Release001", prefix)).map(&to_path);
+
+  Some(PipeFiles { key: key, attack: to_path(path), release: release })
+}
+
+/// Los tubos de un rango, de first_pipe en adelante, para las teclas
+/// desde first_key. Las teclas son números lógicos del manual.
+fn rank_pipes (
+  rank: &Section,
+  base: &Path,
+  first_pipe: usize,
+  count: usize,
+  first_key: usize,
+  first_midi: i32,
+) -> Vec<PipeFiles> {
+  (0..count).filter_map(|i| {
+    let note = first_midi + (first_key + i) as i32 - 1;
+    if note < 0 || note > 127 { return None; }
+    pipe_files(rank, first_pipe + i, base, note as u8)
+  }).collect()
+}
+
+fn read_stop (ini: &Ini, number: usize, base: &Path, first_midi: i32) -> Result<OdfStop, OdfError> {
+  let stop = ini.section(&format!("Stop{:03}", number))?;
+  let name = stop.string("Name")?;
+
+  let mut ranks = Vec::new();
+
+  if stop.get("NumberOfRanks").is_some() {
+    // Registro nuevo, con secciones [RankNNN]
+    let first_key = stop.number_or("FirstAccessiblePipeLogicalKeyNumber", 1)?;
+    for (i, rank_number) in stop.refs("NumberOfRanks", "Rank")?.into_iter().enumerate() {
+      let rank = ini.section(&format!("Rank{:03}", rank_number))?;
+      let prefix = format!("Rank{:03}", i + 1);
+
+      let total: usize = rank.number_or("NumberOfLogicalPipes", 0)?;
+      let first_pipe = stop.number_or(&format!("{}FirstPipeNumber", prefix), 1)?;
+      let count = stop.number_or(&format!("{}PipeCount", prefix), (total + 1).saturating_sub(first_pipe))?;
+      let rank_key = stop.number_or(&format!("{}FirstAccessibleKeyNumber", prefix), 1)?;
+
+      ranks.push(OdfRank {
+        name: rank.string("Name").unwrap_or_else(|_| name.clone()),
+        gain: gain(&rank)? * gain(&stop)?,
+        pipes: rank_pipes(&rank, base, first_pipe, count, first_key + rank_key - 1, first_midi),
+      });
+    }
+  } else {
+    // Registro viejo, que es su propio rango
+    let total: usize = stop.number_or("NumberOfLogicalPipes", 0)?;
+    let first_pipe = stop.number_or("FirstAccessiblePipeLogicalPipeNumber", 1)?;
+    let first_key = stop.number_or("FirstAccessiblePipeLogicalKeyNumber", 1)?;
+    let count = stop.number_or("NumberOfAccessiblePipes", (total + 1).saturating_sub(first_pipe))?;
+
+    ranks.push(OdfRank {
+      name: name.clone(),
+      gain: gain(&stop)?,
+      pipes: rank_pipes(&stop, base, first_pipe, count, first_key, first_midi),
+    });
+  }
+
+  Ok(OdfStop { name: name, ranks: ranks })
+}
+
+fn read_coupler (ini: &Ini, number: usize) -> Result<OdfCoupler, OdfError> {
+  let coupler = ini.section(&format!("Coupler{:03}", number))?;
+  Ok(OdfCoupler {
+    name: coupler.string("Name")?,
+    destination: coupler.number_or("DestinationManual", 0)?,
+    keyshift: coupler.number_or("DestinationKeyshift", 0)?,
+    unison_off: coupler.flag("UnisonOff"),
+  })
+}
+
+fn read_manual (ini: &Ini, number: usize, base: &Path) -> Result<OdfManual, OdfError> {
+  let manual = ini.section(&format!("Manual{:03}", number))?;
+
+  // La nota midi de la tecla lógica 1
+  let first_midi = manual.number_or("FirstAccessibleKeyMIDINoteNumber", DEFAULT_FIRST_MIDI)?
+    - manual.number_or("FirstAccessibleKeyLogicalKeyNumber", 1)? + 1;
+
+  let stops = manual.refs("NumberOfStops", "Stop")?.into_iter()
+    .map(|n| read_stop(ini, n, base, first_midi))
+    .collect::<Result<_, _>>()?;
+
+  let couplers = manual.refs("NumberOfCouplers", "Coupler")?.into_iter()
+    .map(|n| read_coupler(ini, n))
+    .collect::<Result<_, _>>()?;
+
+  Ok(OdfManual {
+    number: number,
+    name: manual.string("Name")?,
+    stops: stops,
+    couplers: couplers,
+  })
+}
+
+/// Lee un ODF. Solo lee el archivo .organ, los samples se leen después
+/// en otro hilo, al crear los tubos.
+pub fn load (path: &Path) -> Result<OdfOrgan, OdfError> {
+  let mut bytes = Vec::new();
+  File::open(path)
+    .and_then(|mut file| file.read_to_end(&mut bytes))
+    .map_err(|err| OdfError::Io(path.to_path_buf(), err))?;
+
+  // Hay ODF en UTF-8 con BOM y en Latin-1, lo que no se pueda leer solo
+  // afecta a los nombres.
+  let text = String::from_utf8_lossy(&bytes);
+  let ini = Ini::parse(text.trim_left_matches('\u{FEFF}'));
+
+  let base = path.parent().unwrap_or(Path::new("."));
+  let organ = ini.section("Organ")?;
+
+  let count: usize = organ.number_or("NumberOfManuals", 0)?;
+  let first = if organ.flag("HasPedals") { 0 } else { 1 };
+
+  let manuals = (first..count+1)
+    .map(|n| read_manual(&ini, n, base))
+    .collect::<Result<_, _>>()?;
+
+  Ok(OdfOrgan {
+    name: organ.string("ChurchName").unwrap_or_default(),
+    manuals: manuals,
+  })
+}
+
+/// El número de manual del ODF que toca cada división: el primer manual
+/// es el Great, el segundo el Swell, y el pedal es el manual 0.
+pub const MANUAL_NUMBERS: [usize; MANUAL_COUNT] = [1, 2, 0];
+
+/// La división de un manual del ODF, vacía si el órgano no lo tiene.
+fn division (organ: &OdfOrgan, index: usize) -> Division {
+  let number = MANUAL_NUMBERS[index];
+  let manual = match organ.manuals.iter().find(|m| m.number == number) {
+    Some(manual) => manual,
+    None => return Division::from_stops(Vec::new()),
+  };
+
+  if manual.stops.len() > STOP_SLOTS {
+    warn(format!("{} has {} stops, only the first {} are used", manual.name, manual.stops.len(), STOP_SLOTS));
+  }
+
+  let mut rank_count = 0;
+  let stops = manual.stops.iter().take(STOP_SLOTS).map(|stop| {
+    let pipes = stop.ranks.iter().filter_map(|rank| {
+      if rank_count >= RANK_SLOTS {
+        warn(format!("{}: rank '{}' does not fit and is ignored", manual.name, rank.name));
+        return None;
+      }
+      let def = RankDef {
+        footage: 8.0, color: 0.5, gain: rank.gain,
+        attack: 0.002, release: 5.0, chiff: 0.0, chiff_len: 0.0,
+        scale: 0.0,
+        detune: 0.0,
+        layout: Layout::Split,
+        kind: Kind::Sampled(&sampled::EXTERNAL),
+        breaks: &[],
+        voicing: &[],
+      };
+      let seed = index as u32 * RANK_SLOTS as u32 + rank_count as u32;
+      rank_count += 1;
+
+      let name = format!("{} {}", MANUAL_NAMES[index], rank.name);
+      Some(Pipe::with_source(&def, seed, Source::Pipes(rank.pipes.clone()), name))
+    }).collect();
+    (stop.name.clone(), pipes)
+  }).collect();
+
+  Division::from_stops(stops)
+}
+
+/// Las divisiones del órgano, en el orden de MANUAL_NAMES.
+pub fn divisions (organ: &OdfOrgan) -> [Division; MANUAL_COUNT] {
+  for manual in organ.manuals.iter() {
+    if !MANUAL_NUMBERS.contains(&manual.number) {
+      warn(format!("manual '{}' has no division and is ignored", manual.name));
+    }
+  }
+  [division(organ, 0), division(organ, 1), division(organ, 2)]
+}
+
+/// Los acopladores entre los manuales que tienen división. Los de otros
+/// manuales se ignoran.
+pub fn couplers (organ: &OdfOrgan) -> Vec<Coupler> {
+  let division = |number| MANUAL_NUMBERS.iter().position(|&n| n == number);
+
+  let mut couplers = Vec::new();
+  for manual in organ.manuals.iter() {
+    let source = match division(manual.number) {
+      Some(source) => source,
+      None => continue,
+    };
+
+    for c in manual.couplers.iter() {
+      if c.unison_off {
+        couplers.push(Coupler::unison_off(c.name.clone(), source));
+        continue;
+      }
+      match division(c.destination) {
+        Some(destination) => couplers.push(Coupler::new(c.name.clone(), source, destination, c.keyshift)),
+        None => warn(format!("coupler '{}' goes to a manual without division and is ignored", c.name)),
+      }
+    }
+  }
+
+  if couplers.len() > COUPLER_SLOTS {
+    warn(format!("the organ has {} couplers, only the first {} are used", couplers.len(), COUPLER_SLOTS));
+    couplers.truncate(COUPLER_SLOTS);
+  }
+  couplers
+}
+
+/// Reporta un ODF que no se pudo cargar.
+pub fn report (path: &Path, err: &OdfError) {
+  warn(format!("{} failed to load: {}", path.display(), err));
+}
@@ -1,21 +1,134 @@
 
 use arnaudio::synth::*;
 use helpers::*;
+use sample::Sample;
 use arnaudio::voice;
 
 use effects::vibrato::Vibrato;
-use effects::leslie::Leslie;
+use effects::leslie::{Leslie, Speed as LeslieSpeed};
+use effects::cabinet::Cabinet;
 use effects::room::Room;
 use effects::Waver;
+use effects::expression::Expression;
+use effects::swell::SwellBox;
+
+use wind::Chest;
 
 use hammond::{Hammond, Osc as HOsc};
-use pipe::{Pipe, Osc as POsc};
+use division::{Division, MANUAL_COUNT, STOP_SLOTS, MANUAL_NAMES, ENCLOSED, DEFAULT_WIDTH};
+use coupler::{self, Coupler, Keyboard, COUPLER_SLOTS};
+use crescendo::Crescendo;
+use combination::{Memory, Piston, GENERAL_SLOTS, DIVISIONAL_SLOTS};
+use additive::{self, SPECTRUM_SIZE};
+use pipe::FormCache;
+use odf;
+use arnaudio::state::{Writer, Reader, EndOfData};
+
+use std::path::Path;
 
 const WHEEL_COUNT: usize = 9;
-const PIPE_COUNT: usize = 5;
-const PIPE_PARAMS: usize = 6;
 const FIRST_PARAMS: usize = 21;
 
+// Un parámetro por cada registro de cada manual, van después de los wheels
+const FIRST_STOP: usize = FIRST_PARAMS + WHEEL_COUNT;
+const STOP_COUNT: usize = MANUAL_COUNT * STOP_SLOTS;
+
+// Parámetros que van después de los registros
+const EXPRESSION: usize = FIRST_STOP + STOP_COUNT;
+const EXPRESSION_MIN: usize = EXPRESSION + 1;
+const WIND_STABILITY: usize = EXPRESSION_MIN + 1;
+const WIND_DEPTH: usize = WIND_STABILITY + 1;
+
+// Velocidad y profundidad del trémolo de cada manual
+const FIRST_TREMULANT: usize = WIND_DEPTH + 1;
+const TREMULANT_COUNT: usize = MANUAL_COUNT * 2;
+
+const PIPE_WIDTH: usize = FIRST_TREMULANT + TREMULANT_COUNT;
+
+// Un parámetro por cada acoplador
+const FIRST_COUPLER: usize = PIPE_WIDTH + 1;
+
+const SWELL: usize = FIRST_COUPLER + COUPLER_SLOTS;
+const CRESCENDO: usize = SWELL + 1;
+const TUTTI: usize = CRESCENDO + 1;
+
+// El interruptor del leslie. El pedal cambia entre coral y trémolo cada
+// vez que se presiona, como el sustain de un piano.
+const LESLIE_SPEED: usize = TUTTI + 1;
+const LESLIE_PEDAL: usize = LESLIE_SPEED + 1;
+const LESLIE_BRAKE: usize = LESLIE_PEDAL + 1;
+
+// Aceleración y frenado de la corneta y del tambor
+const FIRST_LESLIE_RAMP: usize = LESLIE_BRAKE + 1;
+const LESLIE_RAMP_COUNT: usize = 4;
+
+// La velocidad actual de cada rotor, solo para mostrarla. Cambiarlos no
+// hace nada.
+const HORN_SPEED: usize = FIRST_LESLIE_RAMP + LESLIE_RAMP_COUNT;
+const DRUM_SPEED: usize = HORN_SPEED + 1;
+
+// Los micrófonos del leslie. Los de la corneta son los parámetros 9 a 11.
+const LESLIE_DISTANCE: usize = DRUM_SPEED + 1;
+const LESLIE_BALANCE: usize = LESLIE_DISTANCE + 1;
+const DRUM_ANGLE: usize = LESLIE_BALANCE + 1;
+const DRUM_AM: usize = DRUM_ANGLE + 1;
+const DRUM_DOPPLER: usize = DRUM_AM + 1;
+const LESLIE_SPLIT: usize = DRUM_DOPPLER + 1;
+
+// La respuesta del gabinete, y si reemplaza al leslie
+const CABINET: usize = LESLIE_SPLIT + 1;
+const STATIC_CABINET: usize = CABINET + 1;
+
+// El chiff de todos los tubos, en el medio suenan como fueron entonados
+const PIPE_CHIFF: usize = STATIC_CABINET + 1;
+const PIPE_CHIFF_LEN: usize = PIPE_CHIFF + 1;
+
+// El rango con espectro que se está editando, y la amplitud de cada uno
+// de sus armónicos. Los armónicos muestran los del rango elegido, y el
+// espectro de cada rango se guarda en el estado, no en los parámetros.
+const SPECTRUM_RANK: usize = PIPE_CHIFF_LEN + 1;
+const FIRST_HARMONIC: usize = SPECTRUM_RANK + 1;
+
+// El color de todos los tubos, en el medio suenan como fueron entonados
+const PIPE_COLOR: usize = FIRST_HARMONIC + SPECTRUM_SIZE;
+
+// Si los tubos pasan por el vibrato y el leslie, como el hammond
+const PIPE_LESLIE: usize = PIPE_COLOR + 1;
+
+const PARAM_COUNT: usize = PIPE_LESLIE + 1;
+
+/// Control Change del pedal de expresión.
+const CC_EXPRESSION: u8 = 11;
+/// Control Change del pedal de la caja expresiva (Foot Controller).
+const CC_SWELL: u8 = 4;
+/// Control Change del crescendo, que no tiene uso en el estándar.
+const CC_CRESCENDO: u8 = 3;
+/// El leslie se controla con la rueda de modulación, o con el pedal de
+/// sustain como interruptor.
+const CC_LESLIE: u8 = 1;
+const CC_LESLIE_PEDAL: u8 = 64;
+
+// Rango de los tiempos de aceleración y frenado del leslie, en segundos.
+const RAMP_MIN_S: f32 = 0.1;
+const RAMP_MAX_S: f32 = 10.0;
+
+/// Cada cuántos samples se avisa la velocidad de los rotores.
+const METER_SAMPLES: usize = 1470;
+
+/// Canal midi de los pistones de la combinación (el 16). Los Program
+/// Change de este canal son los generales, y los de cada manual sus
+/// divisionales.
+const PISTON_CHANNEL: usize = 15;
+
+// Las notas de los pistones en PISTON_CHANNEL. Los generales empiezan en
+// C2, y los divisionales de cada manual en C3, uno después de otro.
+const FIRST_GENERAL_NOTE: u8 = 36;
+const FIRST_DIVISIONAL_NOTE: u8 = 48;
+const SET_NOTE: u8 = 72;
+const PREVIOUS_NOTE: u8 = 74;
+const STEP_NOTE: u8 = 75;
+const NEXT_NOTE: u8 = 76;
+
 #[derive(Default)]
 struct Noise { x: u16 }
 impl Noise {
@@ -38,13 +151,11 @@ struct Voice {
   pub sample: f32,
 
   pub main_osc: HOsc,
-  pub pipe_oscs: [POsc; PIPE_COUNT],
 }
 
 impl voice::Voice for Voice {
   fn is_active(&self) -> bool {
-    self.main_osc.is_active() || 
-    self.pipe_oscs.iter().any(|osc| osc.is_active())
+    self.main_osc.is_active()
   }
 }
 
@@ -53,30 +164,258 @@ pub struct Organ {
 
   gain: f32,
 
+  /// Los drawbars y registros que puso el organista, sin el crescendo
   wheel_gains: [f32; WHEEL_COUNT],
+  stops: [bool; STOP_COUNT],
+  /// Los drawbars como están en el hammond
+  wheel_levels: [f32; WHEEL_COUNT],
+  crescendo: Crescendo,
+
+  /// El valor actual de cada parámetro, para las combinaciones
+  params: [f32; PARAM_COUNT],
+  memory: Memory,
+  /// Parámetros que cambió el órgano, para avisarle al plugin. Tiene
+  /// espacio para todos, y cada uno está una sola vez, ver report.
+  changed: Vec<(usize, f32)>,
   hammond: Hammond,
-  pipes: [Pipe; PIPE_COUNT],
 
+  /// Voces del hammond, que se toca con el primer manual
   voices: voice::Manager<Voice>,
 
+  divisions: [Division; MANUAL_COUNT],
+  /// Los rangos con espectro de todas las divisiones, como (manual, rango)
+  spectra: Vec<(usize, usize)>,
+  /// Las tablas de las formas de los tubos, que comparten los rangos
+  forms: FormCache,
+  /// Las teclas presionadas y los acopladores
+  keyboard: Keyboard,
+  /// La caja expresiva de cada división, si está encerrada
+  swell_boxes: [Option<SwellBox>; MANUAL_COUNT],
+
+  /// El viento que comparten todas las divisiones
+  chest: Chest,
+
   vibrato: Vibrato,
   leslie: Leslie,
+  /// Si el pedal del leslie está presionado
+  leslie_pedal: bool,
+  meter_count: usize,
+  cabinet: Cabinet,
+  /// Si el hammond pasa por el gabinete sin el leslie
+  static_cabinet: bool,
+  /// Si los tubos pasan por la cadena del hammond, ver clock
+  pipe_leslie: bool,
   room: Room,
   waver: Waver,
+  expression: Expression,
+  /// La expresión de los tubos, que están en estéreo
+  pipe_expression: Expression,
 
   noise: Noise,
 }
 
-macro_rules! zip {
-  (mut $a:expr, mut $b:expr) => {
-    $a.iter_mut().zip($b.iter_mut())
-  };
-  (mut $a:expr, $b:expr) => {
-    $a.iter_mut().zip($b.iter())
-  };
-  ($a:expr, $b:expr) => {
-    $a.iter().zip($b.iter())
-  };
+/// Las divisiones y acopladores de un órgano de GrandOrgue si hay uno en
+/// odf::ODF_PATH, y si no los de DISPOSITION.
+fn load_organ () -> ([Division; MANUAL_COUNT], Vec<Coupler>) {
+  let path = Path::new(odf::ODF_PATH);
+  if path.exists() {
+    match odf::load(path) {
+      Ok(organ) => return (odf::divisions(&organ), odf::couplers(&organ)),
+      Err(err) => odf::report(path, &err),
+    }
+  }
+
+  let divisions = [Division::builtin(0), Division::builtin(1), Division::builtin(2)];
+  (divisions, coupler::builtin())
+}
+
+impl Organ {
+  /// Pone un registro como lo dicen el organista y el crescendo. El
+  /// tutti reemplaza lo que puso el organista.
+  fn apply_stop (&mut self, index: usize) {
+    let on = self.crescendo.stop(index) || (!self.crescendo.tutti && self.stops[index]);
+    self.divisions[index/STOP_SLOTS].set_stop(index%STOP_SLOTS, on);
+  }
+
+  fn apply_wheel (&mut self, index: usize) {
+    let level = if self.crescendo.tutti { self.crescendo.wheel(index) }
+      else { self.wheel_gains[index].max(self.crescendo.wheel(index)) };
+
+    // Cambiar un drawbar recalcula la tabla del hammond
+    if level != self.wheel_levels[index] {
+      self.wheel_levels[index] = level;
+      self.hammond.set_gain(index, level);
+    }
+  }
+
+  fn apply_registration (&mut self) {
+    for i in 0..STOP_COUNT { self.apply_stop(i); }
+    for i in 0..WHEEL_COUNT { self.apply_wheel(i); }
+  }
+
+  /// Si el piston guarda el parámetro. Los generales guardan todo menos
+  /// los pedales, y los divisionales los registros y el trémolo de su
+  /// manual.
+  fn piston_param (piston: Piston, index: usize) -> bool {
+    match piston {
+      Piston::Divisional(manual, _) => {
+        let stops = FIRST_STOP + manual*STOP_SLOTS;
+        let tremulant = FIRST_TREMULANT + manual*2;
+        (index >= stops && index < stops + STOP_SLOTS) ||
+          (index >= tremulant && index < tremulant + 2)
+      },
+      _ => match index {
+        EXPRESSION | SWELL | CRESCENDO | TUTTI => false,
+        LESLIE_SPEED | LESLIE_PEDAL | LESLIE_BRAKE => false,
+        HORN_SPEED | DRUM_SPEED => false,
+        // El espectro es parte de la entonación, no de la registración
+        SPECTRUM_RANK => false,
+        _ if index >= FIRST_HARMONIC && index < PIPE_COLOR => false,
+        _ => index < PARAM_COUNT,
+      },
+    }
+  }
+
+  /// Cuántos parámetros guarda el piston.
+  fn piston_size (piston: Piston) -> usize {
+    (0..PARAM_COUNT).filter(|&i| Organ::piston_param(piston, i)).count()
+  }
+
+  /// Con Set presionado guarda la registración en el piston, y si no
+  /// la recuerda. Next y Previous solo recuerdan.
+  fn press (&mut self, piston: Piston) {
+    let store = self.memory.set && match piston {
+      Piston::Next | Piston::Previous => false,
+      _ => true,
+    };
+
+    if store {
+      let params = &self.params;
+      if let Some(snapshot) = self.memory.get_mut(piston) {
+        // Tiene reservado lugar para todos, así que no crece
+        snapshot.values.clear();
+        snapshot.values.extend((0..PARAM_COUNT)
+          .filter(|&i| Organ::piston_param(piston, i))
+          .map(|i| (i, params[i])));
+        snapshot.stored = true;
+      }
+      return;
+    }
+
+    self.memory.advance(piston);
+    let len = match self.memory.get(piston) {
+      Some(snapshot) if snapshot.stored => snapshot.values.len(),
+      _ => return,
+    };
+    for n in 0..len {
+      let (i, value) = match self.memory.get(piston) {
+        Some(snapshot) => snapshot.values[n],
+        None => return,
+      };
+      // Un estado viejo puede tener parámetros que el piston ya no guarda
+      if !Organ::piston_param(piston, i) { continue; }
+      if self.params[i] != value {
+        self.set_param(i, value);
+        self.report(i, value);
+      }
+    }
+  }
+
+  /// Avisa al plugin que el órgano cambió un parámetro. Si ya había
+  /// cambiado se reemplaza el valor, así changed nunca crece más allá de
+  /// lo que tiene reservado, y no reserva memoria en el hilo de audio.
+  fn report (&mut self, index: usize, value: f32) {
+    match self.changed.iter().position(|&(i, _)| i == index) {
+      Some(pos) => self.changed[pos].1 = value,
+      None => self.changed.push((index, value)),
+    }
+  }
+
+  fn apply_leslie_speed (&mut self) {
+    let speed = if self.params[LESLIE_BRAKE] > 0.5 { LeslieSpeed::Stop }
+      else if self.params[LESLIE_SPEED] > 0.5 { LeslieSpeed::Fast }
+      else { LeslieSpeed::Slow };
+    self.leslie.set_speed(speed);
+  }
+
+  /// Avisa la velocidad de los rotores si cambió, para el editor.
+  fn report_rotors (&mut self) {
+    let speeds = [
+      (HORN_SPEED, Leslie::knob_speed(&self.leslie.horn)),
+      (DRUM_SPEED, Leslie::knob_speed(&self.leslie.drum)),
+    ];
+    for &(index, value) in speeds.iter() {
+      if self.params[index] != value {
+        self.params[index] = value;
+        self.report(index, value);
+      }
+    }
+  }
+
+  /// El rango que elige SPECTRUM_RANK, si hay alguno.
+  fn spectrum_rank (&self) -> Option<(usize, usize)> {
+    if self.spectra.is_empty() { return None; }
+    let last = self.spectra.len() - 1;
+    let i = (self.params[SPECTRUM_RANK] * last as f32).round() as usize;
+    Some(self.spectra[i.min(last)])
+  }
+
+  /// Pone en los parámetros los armónicos del rango elegido, para que el
+  /// host y el editor muestren los suyos.
+  fn report_harmonics (&mut self) {
+    let (manual, rank) = match self.spectrum_rank() {
+      Some(selected) => selected,
+      None => return,
+    };
+    let mut harmonics = [0.0; SPECTRUM_SIZE];
+    if let Some(values) = self.divisions[manual].harmonics(rank) {
+      for (h, &value) in harmonics.iter_mut().zip(values.iter()) { *h = value; }
+    }
+    for (i, &value) in harmonics.iter().enumerate() {
+      if self.params[FIRST_HARMONIC + i] != value {
+        self.params[FIRST_HARMONIC + i] = value;
+        self.report(FIRST_HARMONIC + i, value);
+      }
+    }
+  }
+
+  /// Lee los espectros guardados por get_state, en el orden de spectra.
+  fn read_spectra (&mut self, reader: &mut Reader) -> Result<(), EndOfData> {
+    let count = reader.u32()? as usize;
+    for s in 0..count {
+      let size = reader.u32()? as usize;
+      for h in 0..size {
+        let value = reader.f32()?;
+        if let Some(&(manual, rank)) = self.spectra.get(s) {
+          self.divisions[manual].set_harmonic(rank, h, value);
+        }
+      }
+    }
+    Ok(())
+  }
+
+  fn piston_note (&mut self, note: u8, on: bool) {
+    if note == SET_NOTE { self.memory.set = on; return; }
+    if !on { return; }
+
+    let divisional = note.wrapping_sub(FIRST_DIVISIONAL_NOTE) as usize;
+    let general = note.wrapping_sub(FIRST_GENERAL_NOTE) as usize;
+
+    let piston = match note {
+      PREVIOUS_NOTE => Piston::Previous,
+      STEP_NOTE => Piston::Step,
+      NEXT_NOTE => Piston::Next,
+      _ if divisional < MANUAL_COUNT*DIVISIONAL_SLOTS =>
+        Piston::Divisional(divisional/DIVISIONAL_SLOTS, divisional%DIVISIONAL_SLOTS),
+      _ if general < GENERAL_SLOTS => Piston::General(general),
+      _ => return,
+    };
+    self.press(piston);
+  }
+}
+
+fn swell_box (manual: usize) -> Option<SwellBox> {
+  if ENCLOSED[manual] { Some(SwellBox::new()) } else { None }
 }
 
 impl Synth for Organ {
@@ -87,11 +426,19 @@ impl Synth for Organ {
       name: "Basic Plugin".to_string(),
       author: "Arnaud".to_string(),
       id: 42,
-      params: FIRST_PARAMS + WHEEL_COUNT + PIPE_COUNT*PIPE_PARAMS,
+      params: PARAM_COUNT,
     }
   }
   
   fn new () -> Organ {
+    let (divisions, couplers) = load_organ();
+    let crescendo = Crescendo::new(&divisions);
+    let spectra = divisions.iter().enumerate()
+      .flat_map(|(manual, div)| div.spectrum_ranks().into_iter().map(move |rank| (manual, rank)))
+      .collect::<Vec<_>>();
+    additive::spawn_worker(spectra.iter()
+      .filter_map(|&(manual, rank)| divisions[manual].spectrum(rank)));
+
     Organ {
       sample_rate: 44200_f32,
 
@@ -99,14 +446,39 @@ impl Synth for Organ {
       hammond: Hammond::new(),
 
       wheel_gains: [0.0; WHEEL_COUNT],
-      pipes: Default::default(),
+      stops: [false; STOP_COUNT],
+      // Así el primer cambio siempre llega al hammond
+      wheel_levels: [::std::f32::NAN; WHEEL_COUNT],
+      crescendo: crescendo,
+
+      params: [0.0; PARAM_COUNT],
+      memory: Memory::new(
+        Organ::piston_size(Piston::Step),
+        Organ::piston_size(Piston::Divisional(0, 0))
+      ),
+      changed: Vec::with_capacity(PARAM_COUNT),
 
       voices: Default::default(),
 
+      divisions: divisions,
+      spectra: spectra,
+      forms: FormCache::new(),
+      keyboard: Keyboard::new(couplers),
+      swell_boxes: [swell_box(0), swell_box(1), swell_box(2)],
+
+      chest: Chest::new(),
+
       vibrato: Vibrato::new(),
       leslie: Leslie::new(),
+      leslie_pedal: false,
+      meter_count: 0,
+      cabinet: Cabinet::new(),
+      static_cabinet: false,
+      pipe_leslie: false,
       room: Room::new(),
       waver: Waver::new(),
+      expression: Expression::new(),
+      pipe_expression: Expression::new(),
 
       noise: Default::default(),
     }
@@ -116,10 +488,18 @@ impl Synth for Organ {
     let fs = arch.sample_rate;
     self.sample_rate = fs;
     self.hammond.set_sample_rate(fs);
+    for div in self.divisions.iter_mut() { div.set_sample_rate(fs); }
+    for swell in self.swell_boxes.iter_mut().filter_map(|s| s.as_mut()) {
+      swell.set_sample_rate(fs);
+    }
+    self.chest.set_sample_rate(fs);
     self.vibrato.set_sample_rate(fs);
     self.leslie.set_sample_rate(fs);
+    self.cabinet.set_sample_rate(fs);
     self.room.set_sample_rate(fs);
     self.waver.set_sample_rate(fs);
+    self.expression.set_sample_rate(fs);
+    self.pipe_expression.set_sample_rate(fs);
   }
 
   #[inline]
@@ -130,54 +510,162 @@ impl Synth for Organ {
     self.hammond.set_noise(noise);
 
     for voice in self.voices.iter_mut() {
-      let mut v_smpl = 0.0;
-
-      v_smpl += self.hammond.run(&mut voice.main_osc);
-
-      for (mut osc, pipe) in zip!(mut voice.pipe_oscs, self.pipes) {
-        v_smpl += pipe.clock(&mut osc);
-      }
+      smpl += self.hammond.run(&mut voice.main_osc) * voice.gain;
+    }
 
-      smpl += v_smpl * voice.gain;
+    let wind = self.chest.wind();
+    let mut load = 0.0;
+    let mut pipes = Sample::zero();
+    for (div, swell) in self.divisions.iter_mut().zip(self.swell_boxes.iter_mut()) {
+      div.set_noise(noise);
+      // Cada división es un bus aparte, para las que tienen caja
+      let bus = div.clock(&wind);
+      pipes = pipes + match *swell {
+        Some(ref mut swell) => swell.run(bus),
+        None => bus,
+      };
+      load += div.load();
     }
+    self.chest.clock(load);
 
     /*unsafe {
       smpl = ns;
       if ns != 0.0 { ns = 0.0; }
     }*/
 
-    smpl = smpl*self.gain;
+    // Con Pipes Through Leslie los tubos se suman en mono a la cadena del
+    // hammond. La mezcla deja con su volumen a los tubos del centro, que
+    // suenan en cada canal a 1/√2.
+    let mono = if self.pipe_leslie {
+      let (pl, pr) = pipes.to_tuple();
+      (pl + pr) * ::std::f32::consts::FRAC_1_SQRT_2
+    } else { 0.0 };
+
+    smpl = self.expression.run((smpl + mono) * self.gain);
 
     smpl = self.vibrato.run(smpl);
 
     //smpl = self.waver.clock(smpl);
-    let (l, r) = (smpl, smpl);
-    
-    let (l, r) = self.leslie.run(smpl);
-    let (l, r) = self.room.clock(l, r);
+    let (l, r) = if self.static_cabinet { (smpl, smpl) }
+      else { self.leslie.run(smpl) };
+    let (l, r) = self.cabinet.run(l, r);
+
+    self.meter_count += 1;
+    if self.meter_count >= METER_SAMPLES {
+      self.meter_count = 0;
+      self.report_rotors();
+      for div in self.divisions.iter_mut() { div.poll(); }
+      if self.forms.poll() {
+        for div in self.divisions.iter_mut() { div.update_forms(&mut self.forms); }
+      }
+    }
+
+    // Si no, los tubos no pasan por el vibrato, el leslie ni el gabinete.
+    // Esos son parte del hammond, un órgano de tubos no suena por un
+    // leslie, y además lo dejarían en la imagen estéreo de los micrófonos
+    // del leslie, perdiendo la posición de cada tubo (ver pipe::Layout).
+    // Sí tienen expresión, una propia porque son estéreo, y comparten la
+    // sala.
+    let (pl, pr) = if self.pipe_leslie { (0.0, 0.0) } else {
+      self.pipe_expression.run_stereo(pipes.scale(self.gain)).to_tuple()
+    };
+
+    let (l, r) = self.room.clock(l + pl, r + pr);
     (l, r)
   }
 
-  fn note_on(&mut self, note: u8, vel: u8) {
+  // Cada canal midi es un manual: 1 es Great, 2 es Swell y 3 es Pedal.
+  // El hammond suena con el primer manual.
+  fn note_on(&mut self, channel: u8, note: u8, vel: u8) {
     unsafe { ns = 1.0; }
-    let mut voice = self.voices.note_on(note);
+    let channel = channel as usize;
+    if channel == PISTON_CHANNEL { return self.piston_note(note, true); }
+    if channel >= MANUAL_COUNT { return; }
+
+    if channel == 0 {
+      let mut voice = self.voices.note_on(note);
 
-    let freq = 440.0 * 2_f32.powf((note as f32 - 69.0) / 12.0);
+      let freq = 440.0 * 2_f32.powf((note as f32 - 69.0) / 12.0);
+
+      voice.gain = vel as f32 / 256.0;
+      self.hammond.note_on(&mut voice.main_osc, freq);
+    }
+
+    self.keyboard.note_on(&mut self.divisions, channel, note, vel);
+  }
 
-    voice.gain = vel as f32 / 256.0;
-    self.hammond.note_on(&mut voice.main_osc, freq);
+  fn note_off(&mut self, channel: u8, note: u8) {
+    let channel = channel as usize;
+    if channel == PISTON_CHANNEL { return self.piston_note(note, false); }
+    if channel >= MANUAL_COUNT { return; }
 
-    for (mut osc, pipe) in zip!(mut voice.pipe_oscs, self.pipes) {
-      pipe.note_on(&mut osc, freq, self.sample_rate);
+    if channel == 0 {
+      match self.voices.note_off(note) {
+        Some(voice) => self.hammond.note_off(&mut voice.main_osc),
+        _ => {}
+      }
     }
+
+    self.keyboard.note_off(&mut self.divisions, channel, note);
+  }
+
+  fn program_change (&mut self, channel: u8, program: u8) {
+    let channel = channel as usize;
+    let program = program as usize;
+    if channel == PISTON_CHANNEL { self.press(Piston::General(program)); }
+    else if channel < MANUAL_COUNT { self.press(Piston::Divisional(channel, program)); }
   }
 
-  fn note_off(&mut self, note: u8) {
-    match self.voices.note_off(note) {
-      Some(voice) => {
-        self.hammond.note_off(&mut voice.main_osc);
-        for osc in voice.pipe_oscs.iter_mut() { osc.release() }
-      }, _ => {}
+  fn changed_params (&mut self, out: &mut [(usize, f32)]) -> usize {
+    let count = self.changed.len().min(out.len());
+    for (slot, change) in out.iter_mut().zip(self.changed.drain(..count)) { *slot = change; }
+    count
+  }
+
+  // Las combinaciones y después el espectro de cada rango en spectra.
+  fn get_state (&self) -> Vec<u8> {
+    let mut writer = Writer::new();
+    let memory = self.memory.save();
+    writer.u32(memory.len() as u32);
+    writer.bytes(&memory);
+
+    writer.u32(self.spectra.len() as u32);
+    for &(manual, rank) in self.spectra.iter() {
+      let harmonics = self.divisions[manual].harmonics(rank).unwrap_or(&[]);
+      writer.u32(harmonics.len() as u32);
+      for &value in harmonics.iter() { writer.f32(value); }
+    }
+    writer.into_bytes()
+  }
+
+  fn set_state (&mut self, data: &[u8]) {
+    let mut reader = Reader::new(data);
+    let memory = reader.u32().and_then(|len| reader.bytes(len as usize));
+    self.memory.load(memory.unwrap_or(&[]));
+    // Un estado sin espectros deja los rangos como están
+    let _ = self.read_spectra(&mut reader);
+    self.report_harmonics();
+  }
+
+  fn cc_param (cc: u8) -> Option<usize> {
+    match cc {
+      CC_EXPRESSION => Some(EXPRESSION),
+      CC_SWELL => Some(SWELL),
+      CC_CRESCENDO => Some(CRESCENDO),
+      CC_LESLIE => Some(LESLIE_SPEED),
+      CC_LESLIE_PEDAL => Some(LESLIE_PEDAL),
+      _ => None
+    }
+  }
+
+  // El pedal del leslie cambia la velocidad al presionarse, y la
+  // velocidad de los rotores solo la reporta el órgano.
+  fn param_restored (index: usize) -> bool {
+    match index {
+      LESLIE_PEDAL | HORN_SPEED | DRUM_SPEED => false,
+      // Los armónicos vienen con el estado
+      _ if index >= FIRST_HARMONIC && index < PIPE_COLOR => false,
+      _ => true,
     }
   }
 
@@ -202,24 +690,62 @@ impl Synth for Organ {
       21 => 0.4,
       22 => 0.1,
 
-      30 => 1.0,
-
-      32 => 0.6,
-      38 => 0.5,
-      45 => 0.5,
-      50 => 0.5,
-      56 => 0.5,
+      30 => 1.0, // Great Principal 8'
 
       14 => 0.7,
       15 => 1.0,
       18 => 0.2,
       19 => 0.2,
 
+      EXPRESSION => 1.0,
+      EXPRESSION_MIN => 0.3,
+
+      WIND_STABILITY => 0.5,
+      WIND_DEPTH => 0.3,
+
+      PIPE_WIDTH => DEFAULT_WIDTH,
+      PIPE_CHIFF => 0.5,
+      PIPE_CHIFF_LEN => 0.5,
+      PIPE_COLOR => 0.5,
+
+      // Al crear el plugin se eligen los armónicos del primer rango con
+      // espectro, la Viola da Gamba del Pedal, y deben quedar como están.
+      _ if index >= FIRST_HARMONIC && index < PIPE_COLOR => {
+        additive::STRING.harmonics.get(index - FIRST_HARMONIC).cloned().unwrap_or(0.0)
+      }
+
+      SWELL => 1.0,
+
+      // Trémolo a 6.7Hz y 5.7Hz
+      7 => 0.33,
+      8 => 0.28,
+
+      // Los micrófonos como sonaba el leslie antes de poder moverlos
+      10 => 0.6,
+      11 => 0.5,
+      LESLIE_DISTANCE => 0.5,
+      LESLIE_BALANCE => 0.5,
+      DRUM_AM => 0.25,
+      DRUM_DOPPLER => 0.5,
+      // 880Hz
+      LESLIE_SPLIT => 0.55,
+
+      // 1s y 1.6s para la corneta, 5s y 4s para el tambor
+      FIRST_LESLIE_RAMP => 0.5,
+      _ if index == FIRST_LESLIE_RAMP + 1 => 0.6,
+      _ if index == FIRST_LESLIE_RAMP + 2 => 0.85,
+      _ if index == FIRST_LESLIE_RAMP + 3 => 0.8,
+
+      _ if index >= FIRST_TREMULANT && index < PIPE_WIDTH => {
+        // Velocidad a 6Hz, sin profundidad
+        if (index - FIRST_TREMULANT)%2 == 0 { 0.5 } else { 0.0 }
+      }
+
       _ => 0.0
     }
   }
 
-  fn param_name (index: usize) -> String {
+  fn param_name (&self, index: usize) -> String {
     match index {
       0 => "Warm".to_string(),
       1 => "Cold".to_string(),
@@ -230,12 +756,11 @@ impl Synth for Organ {
       5 => "Vibrato Freq".to_string(),
       6 => "Vibrato Mix".to_string(),
 
-      7 => "Leslie Upper Freq".to_string(),
-      8 => "Leslie Lower Freq".to_string(),
-      9 => "Leslie Spread".to_string(),
-
-      10 => "Leslie Tremolo Separation".to_string(),
-      11 => "Leslie Vibrato Separation".to_string(),
+      7 => "Leslie Horn Fast".to_string(),
+      8 => "Leslie Drum Fast".to_string(),
+      9 => "Leslie Horn Mic Angle".to_string(),
+      10 => "Leslie Horn AM Depth".to_string(),
+      11 => "Leslie Horn Doppler Depth".to_string(),
 
       12 => "Waver Depth".to_string(),
       13 => "Waver Mix".to_string(),
@@ -248,28 +773,90 @@ impl Synth for Organ {
       19 => "Room Mix".to_string(),
 
       20 => "Click".to_string(),
+
+      EXPRESSION => "Expression".to_string(),
+      EXPRESSION_MIN => "Expression Min".to_string(),
+
+      WIND_STABILITY => "Wind Stability".to_string(),
+      WIND_DEPTH => "Wind Depth".to_string(),
+
+      PIPE_WIDTH => "Pipe Width".to_string(),
+
+      SWELL => "Swell Pedal".to_string(),
+      CRESCENDO => "Crescendo".to_string(),
+      TUTTI => "Tutti".to_string(),
+
+      LESLIE_SPEED => "Leslie Speed".to_string(),
+      LESLIE_PEDAL => "Leslie Speed Pedal".to_string(),
+      LESLIE_BRAKE => "Leslie Brake".to_string(),
+      HORN_SPEED => "Leslie Horn Speed".to_string(),
+      DRUM_SPEED => "Leslie Drum Speed".to_string(),
+
+      LESLIE_DISTANCE => "Leslie Mic Distance".to_string(),
+      LESLIE_BALANCE => "Leslie Horn/Drum Balance".to_string(),
+      DRUM_ANGLE => "Leslie Drum Mic Angle".to_string(),
+      DRUM_AM => "Leslie Drum AM Depth".to_string(),
+      DRUM_DOPPLER => "Leslie Drum Doppler Depth".to_string(),
+      LESLIE_SPLIT => "Leslie Split Freq".to_string(),
+      // El nombre no puede depender del valor, así que dice las opciones
+      CABINET => format!("Cabinet: Off/{}", self.cabinet.names().join("/")),
+      STATIC_CABINET => "Cabinet Without Leslie".to_string(),
+      PIPE_CHIFF => "Pipe Chiff".to_string(),
+      PIPE_CHIFF_LEN => "Pipe Chiff Len".to_string(),
+      PIPE_COLOR => "Pipe Color".to_string(),
+      PIPE_LESLIE => "Pipes Through Leslie".to_string(),
+      SPECTRUM_RANK => {
+        let names: Vec<String> = self.spectra.iter().map(|&(manual, rank)| {
+          let name = self.divisions[manual].rank_name(rank).unwrap_or("");
+          format!("{} {}", MANUAL_NAMES[manual], name)
+        }).collect();
+        if names.is_empty() { "Spectrum Rank: None".to_string() }
+        else { format!("Spectrum Rank: {}", names.join("/")) }
+      }
+      _ if index >= FIRST_HARMONIC && index < PIPE_COLOR => {
+        format!("Spectrum Harmonic {}", index - FIRST_HARMONIC + 1)
+      }
+
+      _ if index >= FIRST_LESLIE_RAMP && index < HORN_SPEED => {
+        let i = index - FIRST_LESLIE_RAMP;
+        let rotor = if i/2 == 0 { "Horn" } else { "Drum" };
+        if i%2 == 0 { format!("Leslie {} Ramp Up", rotor) }
+        else { format!("Leslie {} Ramp Down", rotor) }
+      }
+
+      _ if index >= FIRST_COUPLER && index < SWELL => {
+        let i = index - FIRST_COUPLER;
+        match self.keyboard.coupler_name(i) {
+          Some(name) => name.to_string(),
+          None => format!("Coupler {}", i+1),
+        }
+      }
+      _ if index >= FIRST_TREMULANT && index < PIPE_WIDTH => {
+        let i = index - FIRST_TREMULANT;
+        let manual = MANUAL_NAMES[i/2];
+        if i%2 == 0 { format!("{} Tremulant Speed", manual) }
+        else { format!("{} Tremulant Depth", manual) }
+      }
       _ => {
         let i = index - FIRST_PARAMS;
         if i < WHEEL_COUNT {
           format!("Wheel {}", i+1)
         } else {
           let i = i-WHEEL_COUNT;
-          let pipe = (i/PIPE_PARAMS)+1;
-          format!("Pipe {} {}", pipe, match i%PIPE_PARAMS {
-            0 => "Gain",
-            1 => "Harm",
-            2 => "Color",
-            3 => "_",
-            4 => "Attack",
-            5 => "Release",
-            _ => unreachable!()
-          })
+          let manual = i/STOP_SLOTS;
+          let slot = i%STOP_SLOTS;
+          match self.divisions[manual].stop_name(slot) {
+            Some(name) => format!("{} {}", MANUAL_NAMES[manual], name),
+            None => format!("{} Stop {}", MANUAL_NAMES[manual], slot+1),
+          }
         }
       }
     }
   }
 
   fn set_param (&mut self, index: usize, value: f32) {
+    if index < PARAM_COUNT { self.params[index] = value; }
+
     match index {
       0 => {},//{self.main_pipe.warm = value; self.main_pipe.regen();},
       1 => {},//{self.main_pipe.cold = value; self.main_pipe.regen();},
@@ -280,11 +867,11 @@ impl Synth for Organ {
       5 => self.vibrato.freq = value,
       6 => self.vibrato.mix = value,
 
-      7 => self.leslie.set_h_freq(value),
-      8 => self.leslie.set_l_freq(value),
-      9 => self.leslie.stereo = value,
-      10 => {},//self.leslie.vol_sep = value,
-      11 => {},//self.leslie.vib_sep = value,
+      7 => self.leslie.set_h_fast(value),
+      8 => self.leslie.set_l_fast(value),
+      9 => self.leslie.horn.set_angle(value),
+      10 => self.leslie.horn.am = value,
+      11 => self.leslie.horn.set_doppler(value),
 
       12 => self.waver.set_depth(value),
       13 => {},
@@ -297,23 +884,93 @@ impl Synth for Organ {
       19 => self.room.mix = value,
 
       20 => self.hammond.set_click(value),
+
+      EXPRESSION => {
+        self.expression.set_value(value);
+        self.pipe_expression.set_value(value);
+      }
+      EXPRESSION_MIN => {
+        self.expression.set_min(value);
+        self.pipe_expression.set_min(value);
+      }
+
+      WIND_STABILITY => self.chest.set_stability(value),
+      WIND_DEPTH => self.chest.set_depth(value),
+
+      PIPE_WIDTH => for div in self.divisions.iter_mut() { div.width = value; },
+
+      SWELL => for swell in self.swell_boxes.iter_mut().filter_map(|s| s.as_mut()) {
+        swell.set_value(value);
+      },
+
+      CRESCENDO => {
+        self.crescendo.set_position(value);
+        self.apply_registration();
+      }
+      TUTTI => {
+        self.crescendo.tutti = value > 0.5;
+        self.apply_registration();
+      }
+
+      LESLIE_SPEED | LESLIE_BRAKE => self.apply_leslie_speed(),
+      LESLIE_PEDAL => {
+        let pressed = value > 0.5;
+        if pressed && !self.leslie_pedal {
+          let speed = if self.params[LESLIE_SPEED] > 0.5 { 0.0 } else { 1.0 };
+          self.set_param(LESLIE_SPEED, speed);
+          self.report(LESLIE_SPEED, speed);
+        }
+        self.leslie_pedal = pressed;
+      }
+      HORN_SPEED | DRUM_SPEED => {},
+
+      LESLIE_DISTANCE => self.leslie.distance = value,
+      LESLIE_BALANCE => self.leslie.set_balance(value),
+      DRUM_ANGLE => self.leslie.drum.set_angle(value),
+      DRUM_AM => self.leslie.drum.am = value,
+      DRUM_DOPPLER => self.leslie.drum.set_doppler(value),
+      LESLIE_SPLIT => self.leslie.set_split(value),
+      CABINET => self.cabinet.select(value),
+      STATIC_CABINET => self.static_cabinet = value > 0.5,
+      PIPE_LESLIE => self.pipe_leslie = value > 0.5,
+      PIPE_CHIFF => for div in self.divisions.iter_mut() { div.set_chiff(value); },
+      PIPE_CHIFF_LEN => for div in self.divisions.iter_mut() { div.set_chiff_len(value); },
+      PIPE_COLOR => {
+        self.forms.start();
+        for div in self.divisions.iter_mut() { div.set_color(value, &mut self.forms); }
+      }
+      SPECTRUM_RANK => self.report_harmonics(),
+      _ if index >= FIRST_HARMONIC && index < PIPE_COLOR => if let Some((manual, rank)) = self.spectrum_rank() {
+        self.divisions[manual].set_harmonic(rank, index - FIRST_HARMONIC, value);
+      },
+
+      _ if index >= FIRST_LESLIE_RAMP && index < HORN_SPEED => {
+        let i = index - FIRST_LESLIE_RAMP;
+        let time = RAMP_MIN_S * (RAMP_MAX_S / RAMP_MIN_S).powf(value);
+        let rotor = if i/2 == 0 { &mut self.leslie.horn } else { &mut self.leslie.drum };
+        if i%2 == 0 { rotor.set_accel(time); }
+        else { rotor.set_decel(time); }
+      }
+
+      _ if index >= FIRST_COUPLER && index < SWELL => {
+        let i = index - FIRST_COUPLER;
+        self.keyboard.set_coupler(&mut self.divisions, i, value > 0.5);
+      }
+      _ if index >= FIRST_TREMULANT && index < PIPE_WIDTH => {
+        let i = index - FIRST_TREMULANT;
+        let tremulant = &mut self.divisions[i/2].tremulant;
+        if i%2 == 0 { tremulant.speed = value; }
+        else { tremulant.depth = value; }
+      }
       _ => {
         let i = index - FIRST_PARAMS;
         if i < WHEEL_COUNT {
-          self.hammond.set_gain(i, value);
+          self.wheel_gains[i] = value;
+          self.apply_wheel(i);
         } else {
           let i = i - WHEEL_COUNT;
-          let pipe = &mut self.pipes[i/PIPE_PARAMS];
-          match i%PIPE_PARAMS {
-            0 => pipe.gain = value,
-            1 => pipe.set_harm(value),
-            2 => pipe.set_color(value),
-            3 => {},
-            4 => {pipe.attack  = value; pipe.calc_params(self.sample_rate);},
-            5 => {pipe.release = value; pipe.calc_params(self.sample_rate);},
-            _ => unreachable!()
-          }
-
+          self.stops[i] = value > 0.5;
+          self.apply_stop(i);
         }
       }
     }
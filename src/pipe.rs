@@ -1,23 +1,53 @@
 
+
 use std::f32::EPSILON;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::thread;
 use helpers::*;
-
-const harmonics: [f32; 23] = [
-  0.25, 0.375, 0.5, 0.625, 0.75, // 5
-  1.0, 1.25, 1.5, 1.75, // 4
-  2.0, 2.5, 2.75, 3.0, 3.25, 3.5, // 6
-  4.0, 4.5, 5.0, 5.5, 6.0, 6.5, 7.0, // 7
-  8.0 // 1
-];
+use hammond::StateVariable;
+use reed::{self, ReedDef, FORMANT_COUNT};
+use flue::{self, FlueDef};
+use sampled::{self, SampledDef, SampleSet, Source};
+use additive::{self, Spectrum, SpectrumDef};
+use wavetable::{self, Wavetable};
+use effects::buffer::Buffer;
+use handoff;
+use wind::Wind;
 
 const WARM: f32 = 60.0;
 const COLD: f32 = 300.0;
 
-// Todas en segundos
-const MIN_ATTACK: f32 = 0.02;
-const MAX_ATTACK: f32 = 0.6;
-const MIN_RELEASE: f32 = 0.02;
-const MAX_RELEASE: f32 = 1.5;
+/// Frecuencia de referencia para la escala de los tubos, ver note_on.
+const SCALE_REF: f32 = 64.0;
+
+/// Máxima desviación aleatoria de la afinación de cada tubo, en cents. Los
+/// tubos de un órgano real nunca están perfectamente afinados, y por eso
+/// los rangos baten un poco entre ellos.
+const RANDOM_CENTS: f32 = 1.5;
+
+// El "vientico" (chiff) que suena cuando el tubo empieza a hablar. Es un
+// ruido filtrado alrededor de un parcial a 2.65 veces la frecuencia del
+// tubo, más una onda seno en ese mismo parcial (ver el readme).
+const CHIFF_RATIO: f32 = 2.65;
+const CHIFF_Q: f32 = 0.3;
+const CHIFF_SINE: f32 = 0.5;
+const CHIFF_NOISE: f32 = 1.0;
+
+// Duración del chiff (hasta -20dB) en segundos, para un tubo a CHIFF_REF.
+const MIN_CHIFF: f32 = 0.005;
+const MAX_CHIFF: f32 = 0.08;
+
+// Los tubos graves tienen un chiff más largo y suave que los agudos. Esta es
+// la frecuencia de referencia, y los exponentes con los que escalan la
+// duración y el volumen respecto a ella.
+const CHIFF_REF: f32 = 512.0;
+const CHIFF_LEN_EXP: f32 = 0.5;
+const CHIFF_VOL_EXP: f32 = 0.3;
+
+// Cuánto alarga o acorta el chiff de todos los rangos el parámetro de
+// duración en sus extremos.
+const CHIFF_LEN_RANGE: f32 = 4.0;
 
 fn sigm (x: f32) -> f32 {
   // para que la derivada en 0 sea 1, hay que usar 4 como punto máximo
@@ -59,79 +89,1042 @@ impl Default for State { fn default () -> State { State::Off } }
 #[derive(Copy, Clone)]
 pub enum Form { Sine, Warm (f32), Cold (f32) }
 
+/// Pasos en que se redondea Color. Los rangos con colores parecidos
+/// comparten las tablas de la forma, ver FormCache.
+const COLOR_STEPS: f32 = 40.0;
+/// Cuántos colores redondeados hay, de 0 a COLOR_STEPS.
+const KEY_COUNT: usize = 41;
+
+/// El color de 0 a 1 redondeado, que identifica a una forma.
+fn color_key (color: f32) -> u32 {
+  (color.max(0.0).min(1.0) * COLOR_STEPS).round() as u32
+}
+
+/// La forma de un color redondeado. En el medio es un seno, arriba es
+/// Warm y abajo Cold.
+fn key_form (key: u32) -> Form {
+  let color = key as f32 / COLOR_STEPS;
+  match color {
+    x if x > 0.5 => Form::Warm(x-0.5),
+    x if x < 0.5 => Form::Cold(0.5-x),
+    _ => Form::Sine
+  }
+}
+
+/// Un período de la forma con un brillo dado, con la fase de 0 a 1.
+fn shape (form: Form, ph: f32, bright: f32) -> f32 {
+  match form {
+    Form::Warm(x) => sin01(tens01(ph, x*WARM*bright)),
+    Form::Cold(x) => tensinv(sin01(ph), x*COLD*bright),
+    Form::Sine => sin01(ph),
+  }
+}
+
+// Bandas de brillo de las tablas de las formas, espaciadas por octavas
+// desde BRIGHT_MIN. Van desde el brillo de la nota más aguda de un 8'
+// hasta el de un pedal de 32', un poco más para el viento.
+const BRIGHT_MIN: f32 = 1.0 / 64.0;
+const BANDS_PER_OCTAVE: f32 = 3.0;
+const BAND_COUNT: usize = 25;
+
+/// log2(x) para x cerca de 1, sin calcular un logaritmo. Para x entre
+/// 0.9 y 1.1 el error es menor que 0.0001.
+fn log2_near_one (x: f32) -> f32 {
+  let u = x - 1.0;
+  (u - u*u/2.0 + u*u*u/3.0) * ::std::f32::consts::LOG2_E
+}
+
+/// Las formas Warm y Cold ya calculadas, para no evaluar la tensión ni
+/// powf en cada sample. Hay una tabla por banda de brillo, y las que
+/// están entre dos bandas se mezclan.
+pub struct FormTables {
+  bands: Vec<Wavetable>,
+}
+
+impl FormTables {
+  pub fn new (form: Form) -> FormTables {
+    FormTables {
+      bands: (0..BAND_COUNT).map(|band| {
+        let bright = BRIGHT_MIN * 2_f32.powf(band as f32 / BANDS_PER_OCTAVE);
+        Wavetable::from_wave(|ph| shape(form, ph, bright))
+      }).collect()
+    }
+  }
+
+  /// La posición de un brillo entre las bandas, sin límites.
+  pub fn band (bright: f32) -> f32 {
+    (bright / BRIGHT_MIN).log2() * BANDS_PER_OCTAVE
+  }
+
+  pub fn read (&self, band: f32, table: usize, phase: f32) -> f32 {
+    let band = band.max(0.0).min((BAND_COUNT - 1) as f32 - 0.0001);
+    let b = band as usize;
+    let (i, frac) = wavetable::position(phase);
+    let (low, high) = (self.bands[b].table(table), self.bands[b+1].table(table));
+    lerp(lerp(low[i], low[i+1], frac), lerp(high[i], high[i+1], frac), band - b as f32)
+  }
+}
+
+/// Lo que comparte FormCache con su hilo, un lugar por color redondeado.
+struct Shared {
+  /// Los colores que faltan, los que se pidieron antes del último cambio
+  /// de color ya no están
+  wanted: [bool; KEY_COUNT],
+  /// Las tablas que calculó el hilo y el cache todavía no tomó
+  ready: Vec<Option<Arc<FormTables>>>,
+  /// Las tablas que ya no usa ningún rango, para borrar en el hilo
+  old: Vec<Option<Arc<FormTables>>>,
+}
+
+/// Las tablas de las formas que usan los rangos, compartidas por todos los
+/// rangos del órgano. Tardan en calcularse, así que las calcula un solo
+/// hilo, una por una (ver handoff.rs). Si los rangos cambian de color antes
+/// de que termine, lo que se pidió antes ya no se calcula.
+pub struct FormCache {
+  /// Las tablas de cada color redondeado, ver color_key
+  tables: Vec<Option<Arc<FormTables>>>,
+  /// Los colores que se pidieron y todavía no llegan
+  wanted: [bool; KEY_COUNT],
+  shared: Arc<Mutex<Shared>>,
+}
+
+impl FormCache {
+  pub fn new () -> FormCache {
+    let shared = Arc::new(Mutex::new(Shared {
+      wanted: [false; KEY_COUNT],
+      ready: (0..KEY_COUNT).map(|_| None).collect(),
+      old: (0..KEY_COUNT).map(|_| None).collect(),
+    }));
+    {
+      let shared = shared.clone();
+      thread::spawn(move || FormCache::work(shared));
+    }
+
+    FormCache {
+      tables: (0..KEY_COUNT).map(|_| None).collect(),
+      wanted: [false; KEY_COUNT],
+      shared: shared,
+    }
+  }
+
+  /// El hilo que calcula las tablas, termina cuando se borra el FormCache.
+  fn work (shared: Arc<Mutex<Shared>>) {
+    while Arc::strong_count(&shared) > 1 {
+      handoff::nap();
+      let (key, old) = {
+        let mut shared = handoff::lock(&shared);
+        let shared = &mut *shared;
+        // También las que llegaron tarde, después de un cambio de color
+        let old: Vec<_> = shared.old.iter_mut().map(|tables| tables.take())
+          .chain(shared.ready.iter_mut().zip(shared.wanted.iter())
+            .map(|(tables, &wanted)| if wanted { None } else { tables.take() }))
+          .collect();
+        let key = (0..KEY_COUNT).find(|&k| shared.wanted[k] && shared.ready[k].is_none());
+        (key, old)
+      };
+      drop(old);
+
+      if let Some(key) = key {
+        let tables = Some(Arc::new(FormTables::new(key_form(key as u32))));
+        let mut shared = handoff::lock(&shared);
+        // Si mientras tanto cambió el color se borran al soltar el lock
+        if shared.wanted[key] { shared.ready[key] = tables; }
+      }
+    }
+  }
+
+  /// Empieza un cambio de color, lo que se pidió antes se descarta.
+  pub fn start (&mut self) {
+    self.wanted = [false; KEY_COUNT];
+  }
+
+  /// Las tablas de un color, o None si todavía no están. En ese caso se
+  /// piden, y llegan con poll.
+  fn get (&mut self, key: u32) -> Option<Arc<FormTables>> {
+    let key = key as usize;
+    if key >= KEY_COUNT { return None; }
+    if self.tables[key].is_none() { self.wanted[key] = true; }
+    self.tables[key].clone()
+  }
+
+  /// Pasa los pedidos al hilo, recibe las tablas que terminó y le
+  /// devuelve las que ya no usa ningún rango. Devuelve true si llegó
+  /// alguna, y entonces los rangos deben buscarlas, ver Pipe::update_forms.
+  /// Si el hilo tiene el lock no hace nada, ya lo hará la próxima vez.
+  pub fn poll (&mut self) -> bool {
+    let mut shared = match handoff::try_lock(&self.shared) {
+      Some(shared) => shared,
+      None => return false,
+    };
+    let shared = &mut *shared;
+
+    let mut arrived = false;
+    for key in 0..KEY_COUNT {
+      let unused = match self.tables[key] {
+        Some(ref tables) => Arc::strong_count(tables) == 1,
+        None => false,
+      };
+      if unused && shared.old[key].is_none() {
+        shared.old[key] = self.tables[key].take();
+      }
+
+      if self.wanted[key] && shared.ready[key].is_some() {
+        self.tables[key] = shared.ready[key].take();
+        self.wanted[key] = false;
+        arrived = true;
+      }
+      shared.wanted[key] = self.wanted[key];
+    }
+    arrived
+  }
+}
+
+/// El tipo de tubo, que decide cómo se genera el sonido.
+#[derive(Copy, Clone)]
+pub enum Kind {
+  /// Tubo labial, suena con la forma dada por Color.
+  Flue,
+  /// Tubo de lengüeta, ver reed.rs.
+  Reed (&'static ReedDef),
+  /// Tubo labial con modelo físico, ver flue.rs.
+  Model (&'static FlueDef),
+  /// Tubo grabado, ver sampled.rs.
+  Sampled (&'static SampledDef),
+  /// Tubo con un espectro dado, ver additive.rs.
+  Additive (&'static SpectrumDef),
+}
+
+/// Cómo se reparten los tubos de un rango a lo ancho del órgano. Los
+/// tubos de un rango casi nunca están en orden: lo normal es que los de
+/// C, D, E... estén en un secreto a un lado y los de C#, D#, F... al otro,
+/// para repartir el peso y el viento.
+#[derive(Copy, Clone)]
+pub enum Layout {
+  /// Todos los tubos en el centro
+  Center,
+  /// Lado de C a la izquierda y lado de C# a la derecha, con los tubos
+  /// grandes afuera y los pequeños en el centro.
+  Split,
+  /// De grave a agudo, de izquierda a derecha
+  Chromatic,
+  /// En mitra, como las fachadas: los tubos grandes en el centro y
+  /// alternando de lado hacia afuera.
+  CentreOut,
+}
+
+// Rango de notas en el que se reparten los tubos, de C a C.
+const LOW_NOTE: u8 = 36;
+const HIGH_NOTE: u8 = 96;
+
+impl Layout {
+  /// Posición del tubo de una nota, de -1 (izquierda) a 1 (derecha).
+  pub fn position (&self, note: u8) -> f32 {
+    let note = note.max(LOW_NOTE).min(HIGH_NOTE);
+    let t = (note - LOW_NOTE) as f32 / (HIGH_NOTE - LOW_NOTE) as f32;
+
+    // Los lados de C y C# son las dos escalas de tonos enteros
+    let side = if note % 2 == 0 { -1.0 } else { 1.0 };
+
+    match *self {
+      Layout::Center => 0.0,
+      Layout::Split => side * lerp(1.0, 0.1, t),
+      Layout::Chromatic => lerp(-1.0, 1.0, t),
+      Layout::CentreOut => side * lerp(0.1, 1.0, t),
+    }
+  }
+}
+
+/// Definición de un rango (rank) de tubos, una fila de tubos del mismo
+/// tipo con uno por tecla.
+#[derive(Clone, Copy)]
+pub struct RankDef {
+  /// Tamaño en pies del tubo de la nota más grave. 8' es el fundamental,
+  /// 4' suena una octava arriba, 2 2/3' una quinta arriba de esa, etc.
+  pub footage: f32,
+
+  /// Timbre, igual que el antiguo knob Color: 0 es Cold (solo armónicos
+  /// impares, como un tubo tapado), 0.5 es Sine y 1 es Warm.
+  pub color: f32,
+  pub gain: f32,
+
+  // El habla del tubo. Attack y Release están en segundos,
+  // chiff y chiff_len van de 0 a 1.
+  pub attack: f32,
+  pub release: f32,
+  pub chiff: f32,
+  pub chiff_len: f32,
+
+  /// Cuántos decibeles cambia el volumen por cada octava que sube el tubo.
+  pub scale: f32,
+
+  /// Desafinación del rango en cents, para los registros ondulantes
+  /// (celestes) que baten contra los demás registros.
+  pub detune: f32,
+
+  /// Dónde están parados los tubos del rango, ver Layout.
+  pub layout: Layout,
+
+  pub kind: Kind,
+
+  /// Quiebres del rango, ordenados por nota.
+  pub breaks: &'static [Break],
+
+  /// Curvas de entonación sobre el teclado, ordenadas por nota. Vacías
+  /// el rango suena igual en todas las teclas.
+  pub voicing: &'static [Voicing],
+}
+
+/// Un quiebre (break) de un rango: desde la nota `note` en adelante el rango
+/// suena con `footage` en vez de su tamaño anterior. Así es como las
+/// mixturas regresan una octava o una quinta en ciertas teclas, para que sus
+/// tubos no se vuelvan demasiado pequeños. Un footage de 0 silencia el rango
+/// desde esa nota.
+#[derive(Clone, Copy)]
+pub struct Break {
+  pub note: u8,
+  pub footage: f32,
+}
+
+/// Un punto de las curvas de entonación (voicing) de un rango. Los tubos
+/// de un rango real no se regulan todos igual: los graves hablan más lento
+/// y con más chiff, y los agudos se hacen más suaves o más fuertes según
+/// el registro. Entre dos puntos las curvas se interpolan linealmente, y
+/// fuera de ellos se quedan en el valor del extremo.
+#[derive(Clone, Copy)]
+pub struct Voicing {
+  pub note: u8,
+  /// Cambio de volumen en decibeles
+  pub gain: f32,
+  // Multiplican el brillo, el tiempo de ataque y release, y el volumen
+  // del chiff del rango. El brillo solo lo usan los tubos labiales
+  // (Kind::Flue).
+  pub bright: f32,
+  pub speech: f32,
+  pub chiff: f32,
+}
+
+/// El punto que no cambia nada.
+const NEUTRAL: Voicing = Voicing { note: 60, gain: 0.0, bright: 1.0, speech: 1.0, chiff: 1.0 };
+
+// Curvas de entonación de la biblioteca. Los principales se suavizan en
+// los agudos, las flautas cantan más arriba, y las lengüetas pierden
+// fuerza hacia los agudos.
+
+const PRINCIPAL_VOICING: &'static [Voicing] = &[
+  Voicing { note: 36, gain: 0.0, bright: 1.0, speech: 1.5, chiff: 1.2 },
+  NEUTRAL,
+  Voicing { note: 96, gain: -1.5, bright: 0.85, speech: 0.7, chiff: 0.8 },
+];
+
+const FLUTE_VOICING: &'static [Voicing] = &[
+  Voicing { note: 36, gain: -0.5, bright: 1.0, speech: 1.6, chiff: 1.3 },
+  NEUTRAL,
+  Voicing { note: 96, gain: 1.0, bright: 1.0, speech: 0.6, chiff: 1.1 },
+];
+
+const REED_VOICING: &'static [Voicing] = &[
+  Voicing { note: 36, gain: 1.5, bright: 1.0, speech: 1.3, chiff: 1.0 },
+  NEUTRAL,
+  Voicing { note: 96, gain: -3.0, bright: 1.0, speech: 0.8, chiff: 1.0 },
+];
+
+/// Número pseudoaleatorio en [0, 1) que depende solo de la semilla, así
+/// la misma tecla siempre suena igual y los renders son deterministas.
+fn hash01 (seed: u32) -> f32 {
+  let mut x = seed.wrapping_mul(2654435761);
+  x ^= x >> 15;
+  x = x.wrapping_mul(2246822519);
+  x ^= x >> 13;
+  (x & 0xFFFF) as f32 / 65536.0
+}
+
+/// Convierte un tamaño en pies al múltiplo de la frecuencia fundamental.
+fn footage_harm (footage: f32) -> f32 {
+  if footage > 0.0 { 8.0 / footage } else { 0.0 }
+}
+
+/// Un registro del órgano, lo que el organista activa con un tirador.
+pub struct StopDef {
+  pub name: &'static str,
+  pub ranks: &'static [RankDef],
+}
+
+impl StopDef {
+  /// Si el registro puede sonar, los grabados necesitan sus archivos.
+  pub fn available (&self) -> bool {
+    self.ranks.iter().all(|rank| match rank.kind {
+      Kind::Sampled(sampled) => sampled.exists(),
+      _ => true,
+    })
+  }
+}
+
+// La biblioteca de registros.
+
+pub const PRINCIPAL_8: StopDef = StopDef {
+  name: "Principal 8'",
+  ranks: &[RankDef {
+    footage: 8.0, color: 0.65, gain: 0.5,
+    attack: 0.05, release: 0.08, chiff: 0.15, chiff_len: 0.3,
+    scale: -1.0,
+    detune: 0.0,
+    layout: Layout::CentreOut,
+    kind: Kind::Flue,
+    breaks: &[],
+    voicing: PRINCIPAL_VOICING,
+  }],
+};
+
+pub const OCTAVE_4: StopDef = StopDef {
+  name: "Octave 4'",
+  ranks: &[RankDef {
+    footage: 4.0, color: 0.65, gain: 0.4,
+    attack: 0.04, release: 0.07, chiff: 0.15, chiff_len: 0.3,
+    scale: -1.0,
+    detune: 0.0,
+    layout: Layout::Split,
+    kind: Kind::Flue,
+    breaks: &[],
+    voicing: PRINCIPAL_VOICING,
+  }],
+};
+
+pub const GEDACKT_8: StopDef = StopDef {
+  name: "Gedackt 8'",
+  ranks: &[RankDef {
+    footage: 8.0, color: 0.3, gain: 0.5,
+    attack: 0.06, release: 0.1, chiff: 0.3, chiff_len: 0.5,
+    scale: -2.0,
+    detune: 0.0,
+    layout: Layout::Split,
+    kind: Kind::Flue,
+    breaks: &[],
+    voicing: FLUTE_VOICING,
+  }],
+};
+
+pub const FLUTE_4: StopDef = StopDef {
+  name: "Flute 4'",
+  ranks: &[RankDef {
+    footage: 4.0, color: 0.55, gain: 0.4,
+    attack: 0.05, release: 0.08, chiff: 0.4, chiff_len: 0.4,
+    scale: -1.5,
+    detune: 0.0,
+    layout: Layout::Split,
+    kind: Kind::Flue,
+    breaks: &[],
+    voicing: FLUTE_VOICING,
+  }],
+};
+
+pub const NAZARD: StopDef = StopDef {
+  name: "Nazard 2 2/3'",
+  ranks: &[RankDef {
+    footage: 8.0/3.0, color: 0.5, gain: 0.3,
+    attack: 0.04, release: 0.07, chiff: 0.3, chiff_len: 0.3,
+    scale: -1.5,
+    detune: 0.0,
+    layout: Layout::Split,
+    kind: Kind::Flue,
+    breaks: &[],
+    voicing: FLUTE_VOICING,
+  }],
+};
+
+pub const TIERCE: StopDef = StopDef {
+  name: "Tierce 1 3/5'",
+  ranks: &[RankDef {
+    footage: 8.0/5.0, color: 0.5, gain: 0.25,
+    attack: 0.04, release: 0.07, chiff: 0.3, chiff_len: 0.3,
+    scale: -1.5,
+    detune: 0.0,
+    layout: Layout::Split,
+    kind: Kind::Flue,
+    breaks: &[],
+    voicing: FLUTE_VOICING,
+  }],
+};
+
+// Registros de lengüeta. El color no se usa, el timbre viene de la
+// definición de la lengüeta.
+
+pub const TRUMPET_8: StopDef = StopDef {
+  name: "Trumpet 8'",
+  ranks: &[RankDef {
+    footage: 8.0, color: 0.5, gain: 0.3,
+    attack: 0.015, release: 0.05, chiff: 0.0, chiff_len: 0.0,
+    scale: 0.0,
+    detune: 0.0,
+    layout: Layout::Chromatic,
+    kind: Kind::Reed(&reed::TRUMPET),
+    breaks: &[],
+    voicing: REED_VOICING,
+  }],
+};
+
+pub const OBOE_8: StopDef = StopDef {
+  name: "Oboe 8'",
+  ranks: &[RankDef {
+    footage: 8.0, color: 0.5, gain: 0.3,
+    attack: 0.02, release: 0.05, chiff: 0.0, chiff_len: 0.0,
+    scale: -0.5,
+    detune: 0.0,
+    layout: Layout::Chromatic,
+    kind: Kind::Reed(&reed::OBOE),
+    breaks: &[],
+    voicing: REED_VOICING,
+  }],
+};
+
+pub const VOX_HUMANA_8: StopDef = StopDef {
+  name: "Vox Humana 8'",
+  ranks: &[RankDef {
+    footage: 8.0, color: 0.5, gain: 0.25,
+    attack: 0.025, release: 0.06, chiff: 0.0, chiff_len: 0.0,
+    scale: -1.0,
+    detune: 0.0,
+    layout: Layout::Chromatic,
+    kind: Kind::Reed(&reed::VOX_HUMANA),
+    breaks: &[],
+    voicing: REED_VOICING,
+  }],
+};
+
+// Registros compuestos. Las notas de los quiebres son notas midi,
+// 36 es la tecla más grave de un manual (C) y 96 la más aguda.
+
+const MIXTURE_RANK: RankDef = RankDef {
+  footage: 2.0, color: 0.65, gain: 0.2,
+  attack: 0.03, release: 0.06, chiff: 0.1, chiff_len: 0.2,
+  scale: -1.0,
+  detune: 0.0,
+  layout: Layout::Split,
+  kind: Kind::Flue,
+  breaks: &[],
+  voicing: PRINCIPAL_VOICING,
+};
+
+pub const MIXTURE_IV: StopDef = StopDef {
+  name: "Mixture IV",
+  ranks: &[
+    RankDef { footage: 4.0/3.0, breaks: &[
+      Break { note: 48, footage: 2.0 },
+      Break { note: 60, footage: 8.0/3.0 },
+      Break { note: 72, footage: 4.0 },
+    ], ..MIXTURE_RANK },
+    RankDef { footage: 1.0, breaks: &[
+      Break { note: 48, footage: 4.0/3.0 },
+      Break { note: 60, footage: 2.0 },
+      Break { note: 72, footage: 8.0/3.0 },
+    ], ..MIXTURE_RANK },
+    RankDef { footage: 2.0/3.0, breaks: &[
+      Break { note: 48, footage: 1.0 },
+      Break { note: 60, footage: 4.0/3.0 },
+      Break { note: 72, footage: 2.0 },
+    ], ..MIXTURE_RANK },
+    RankDef { footage: 0.5, breaks: &[
+      Break { note: 48, footage: 2.0/3.0 },
+      Break { note: 60, footage: 1.0 },
+      Break { note: 72, footage: 4.0/3.0 },
+    ], ..MIXTURE_RANK },
+  ],
+};
+
+const SESQUIALTERA_RANK: RankDef = RankDef {
+  footage: 8.0/3.0, color: 0.5, gain: 0.28,
+  attack: 0.04, release: 0.07, chiff: 0.25, chiff_len: 0.3,
+  scale: -1.5,
+  detune: 0.0,
+  layout: Layout::Split,
+  kind: Kind::Flue,
+  breaks: &[],
+  voicing: FLUTE_VOICING,
+};
+
+pub const SESQUIALTERA_II: StopDef = StopDef {
+  name: "Sesquialtera II",
+  ranks: &[
+    RankDef { footage: 8.0/3.0, breaks: &[
+      Break { note: 84, footage: 16.0/3.0 },
+    ], ..SESQUIALTERA_RANK },
+    RankDef { footage: 8.0/5.0, breaks: &[
+      Break { note: 84, footage: 16.0/5.0 },
+    ], ..SESQUIALTERA_RANK },
+  ],
+};
+
+// El corneta solo suena en la mitad aguda del teclado
+const CORNET_RANK: RankDef = RankDef {
+  footage: 0.0, color: 0.45, gain: 0.3,
+  attack: 0.04, release: 0.07, chiff: 0.3, chiff_len: 0.3,
+  scale: -1.0,
+  detune: 0.0,
+  layout: Layout::Center,
+  kind: Kind::Flue,
+  breaks: &[],
+  voicing: FLUTE_VOICING,
+};
+
+pub const CORNET_V: StopDef = StopDef {
+  name: "Cornet V",
+  ranks: &[
+    RankDef { breaks: &[ Break { note: 60, footage: 8.0 } ], ..CORNET_RANK },
+    RankDef { breaks: &[ Break { note: 60, footage: 4.0 } ], ..CORNET_RANK },
+    RankDef { breaks: &[ Break { note: 60, footage: 8.0/3.0 } ], ..CORNET_RANK },
+    RankDef { breaks: &[ Break { note: 60, footage: 2.0 } ], ..CORNET_RANK },
+    RankDef { breaks: &[ Break { note: 60, footage: 8.0/5.0 } ], ..CORNET_RANK },
+  ],
+};
+
+// Registros ondulantes, se afinan un poco alto o bajo para que batan con
+// los demás registros de 8'. Solo suenan desde el C tenor (48).
+
+pub const VOIX_CELESTE: StopDef = StopDef {
+  name: "Voix Celeste 8'",
+  ranks: &[RankDef {
+    footage: 0.0, color: 0.8, gain: 0.35,
+    attack: 0.06, release: 0.08, chiff: 0.05, chiff_len: 0.2,
+    scale: -1.0,
+    detune: 6.0,
+    layout: Layout::Split,
+    kind: Kind::Flue,
+    breaks: &[ Break { note: 48, footage: 8.0 } ],
+    voicing: PRINCIPAL_VOICING,
+  }],
+};
+
+pub const UNDA_MARIS: StopDef = StopDef {
+  name: "Unda Maris 8'",
+  ranks: &[RankDef {
+    footage: 0.0, color: 0.45, gain: 0.35,
+    attack: 0.07, release: 0.1, chiff: 0.1, chiff_len: 0.3,
+    scale: -1.5,
+    detune: -4.0,
+    layout: Layout::Split,
+    kind: Kind::Flue,
+    breaks: &[ Break { note: 48, footage: 8.0 } ],
+    voicing: PRINCIPAL_VOICING,
+  }],
+};
+
+// Registros con modelo físico. El chiff sale solo del modelo.
+
+pub const HARMONIC_FLUTE_8: StopDef = StopDef {
+  name: "Harmonic Flute 8'",
+  ranks: &[RankDef {
+    footage: 8.0, color: 0.5, gain: 0.5,
+    attack: 0.04, release: 0.08, chiff: 0.0, chiff_len: 0.0,
+    scale: -1.0,
+    detune: 0.0,
+    layout: Layout::Split,
+    kind: Kind::Model(&flue::HARMONIC),
+    breaks: &[],
+    voicing: FLUTE_VOICING,
+  }],
+};
+
+pub const BOURDON_16: StopDef = StopDef {
+  name: "Bourdon 16'",
+  ranks: &[RankDef {
+    footage: 16.0, color: 0.5, gain: 0.6,
+    attack: 0.08, release: 0.1, chiff: 0.0, chiff_len: 0.0,
+    scale: -1.0,
+    detune: 0.0,
+    layout: Layout::Split,
+    kind: Kind::Model(&flue::STOPPED),
+    breaks: &[],
+    voicing: FLUTE_VOICING,
+  }],
+};
+
+// Registros grabados. El ataque y el release ya están en la grabación,
+// el envelope solo evita chasquidos.
+pub const SAMPLED_BASS_16: StopDef = StopDef {
+  name: "Sampled Bass 16'",
+  ranks: &[RankDef {
+    footage: 16.0, color: 0.5, gain: 0.7,
+    attack: 0.002, release: 0.4, chiff: 0.0, chiff_len: 0.0,
+    scale: 0.0,
+    detune: 0.0,
+    layout: Layout::Split,
+    kind: Kind::Sampled(&sampled::PEDAL_BASS),
+    breaks: &[],
+    voicing: &[],
+  }],
+};
+
+/// Un armonio grabado, para mezclar con los tubos. El envelope viene del
+/// SFZ, así que el del rango casi no hace nada.
+pub const HARMONIUM_8: StopDef = StopDef {
+  name: "Harmonium 8'",
+  ranks: &[RankDef {
+    footage: 8.0, color: 0.5, gain: 0.6,
+    attack: 0.002, release: 5.0, chiff: 0.0, chiff_len: 0.0,
+    scale: 0.0,
+    detune: 0.0,
+    layout: Layout::Center,
+    kind: Kind::Sampled(&sampled::HARMONIUM),
+    breaks: &[],
+    voicing: &[],
+  }],
+};
+
+pub const SUBBASS_16: StopDef = StopDef {
+  name: "Subbass 16'",
+  ranks: &[RankDef {
+    footage: 16.0, color: 0.25, gain: 0.6,
+    attack: 0.1, release: 0.12, chiff: 0.2, chiff_len: 0.6,
+    scale: -1.0,
+    detune: 0.0,
+    layout: Layout::Split,
+    kind: Kind::Flue,
+    breaks: &[],
+    voicing: FLUTE_VOICING,
+  }],
+};
+
+// Registros de otros tamaños. El tamaño puede ser cualquier fracción: la
+// Septime suena en el séptimo armónico del 8', y la Gravissima es un 64'
+// resultante, un 32' con su quinta que juntos hacen sonar la nota
+// diferencia una octava abajo.
+
+pub const SEPTIME: StopDef = StopDef {
+  name: "Septime 1 1/7'",
+  ranks: &[RankDef {
+    footage: 8.0/7.0, color: 0.5, gain: 0.25,
+    attack: 0.04, release: 0.07, chiff: 0.25, chiff_len: 0.3,
+    scale: -1.5,
+    detune: 0.0,
+    layout: Layout::Split,
+    kind: Kind::Flue,
+    breaks: &[],
+    voicing: FLUTE_VOICING,
+  }],
+};
+
+const GRAVISSIMA_RANK: RankDef = RankDef {
+  footage: 32.0, color: 0.3, gain: 0.5,
+  attack: 0.15, release: 0.15, chiff: 0.1, chiff_len: 0.7,
+  scale: -1.0,
+  detune: 0.0,
+  layout: Layout::Split,
+  kind: Kind::Flue,
+  breaks: &[],
+  voicing: FLUTE_VOICING,
+};
+
+pub const GRAVISSIMA_64: StopDef = StopDef {
+  name: "Gravissima 64'",
+  ranks: &[
+    GRAVISSIMA_RANK,
+    RankDef { footage: 64.0/3.0, gain: 0.35, ..GRAVISSIMA_RANK },
+  ],
+};
+
+// Registros con espectro propio, ver additive.rs.
+
+pub const VIOLA_DA_GAMBA_8: StopDef = StopDef {
+  name: "Viola da Gamba 8'",
+  ranks: &[RankDef {
+    footage: 8.0, color: 0.5, gain: 0.6,
+    attack: 0.08, release: 0.09, chiff: 0.05, chiff_len: 0.2,
+    scale: -1.0,
+    detune: 0.0,
+    layout: Layout::Split,
+    kind: Kind::Additive(&additive::STRING),
+    breaks: &[],
+    voicing: PRINCIPAL_VOICING,
+  }],
+};
+
+pub const TRAVERSE_FLUTE_8: StopDef = StopDef {
+  name: "Traverse Flute 8'",
+  ranks: &[RankDef {
+    footage: 8.0, color: 0.5, gain: 0.45,
+    attack: 0.05, release: 0.08, chiff: 0.35, chiff_len: 0.4,
+    scale: -1.0,
+    detune: 0.0,
+    layout: Layout::Split,
+    kind: Kind::Additive(&additive::TRAVERSE),
+    breaks: &[],
+    voicing: FLUTE_VOICING,
+  }],
+};
+
 #[derive(Default)]
 pub struct Osc {
   pub phase: f32,
   pub delta: f32,
 
   pub bright: f32,
+  pub gain: f32,
 
   pub vol: f32,
   pub state: State,
+  /// El ataque y el release del tubo, que dependen de la tecla
+  pub a_delta: f32,
+  pub r_delta: f32,
+
+  /// Posición del tubo, de -1 a 1
+  pub pan: f32,
+
+  pub chiff: f32,
+  pub chiff_decay: f32,
+  pub chiff_phase: f32,
+  pub chiff_filter: StateVariable,
+
+  // Estado de las lengüetas
+  pub bend: f32,
+  pub bend_decay: f32,
+  pub formants: [StateVariable; FORMANT_COUNT],
+
+  // Estado del modelo físico
+  pub bore: Buffer<f32>,
+  pub jet: Buffer<f32>,
+  pub bore_s: f32,
+  pub jet_s: f32,
+  pub loop_lp: f32,
+  pub dc_x: f32,
+  pub dc_y: f32,
+
+  /// La tabla de onda que lee el tubo, según su frecuencia
+  pub table: usize,
+  /// La banda de brillo de las tablas de la forma, ver FormTables
+  pub bright_band: f32,
+
+  // Estado de los tubos grabados, la grabación es (rango, índice)
+  pub recording: Option<(Arc<SampleSet>, usize)>,
+  pub play_pos: f64,
+  pub play_step: f64,
+  /// El sinc con que se lee la grabación, según play_step
+  pub play_sinc: usize,
+  /// La grabación del release, si la nota tiene una aparte
+  pub release_rec: Option<usize>,
+  pub release_pos: f64,
+  pub release_step: f64,
+  pub release_sinc: usize,
+  pub fade: f32,
+  pub fade_delta: f32,
+  // El envelope de la grabación, ver sampled::Envelope
+  pub env: f32,
+  pub env_time: f32,
+  pub env_period: f32,
 }
 
 pub struct Pipe {
   pub gain: f32,
   harm: f32,
 
-  //pub color: f32,
+  /// Quiebres como (nota, harm)
+  breaks: Vec<(u8, f32)>,
+  voicing: &'static [Voicing],
+
+  /// El color con que fue entonado el rango, y el que tiene con el
+  /// ajuste de todo el órgano, redondeado (ver color_key)
+  color: f32,
+  color_key: u32,
   form: Form,
+  /// Las tablas de la forma, ver FormCache
+  forms: Option<Arc<FormTables>>,
+  kind: Kind,
 
+  // En segundos
   pub attack: f32,
   pub release: f32,
 
-  a_delta: f32,
-  r_delta: f32,
+  pub chiff: f32,
+  pub chiff_len: f32,
+  /// El ajuste del chiff de todo el órgano, ver set_chiff
+  chiff_scale: f32,
+  chiff_len_scale: f32,
+
+  scale: f32,
+  detune: f32,
+  layout: Layout,
+
+  /// Semilla de las desviaciones aleatorias de los tubos del rango
+  seed: u32,
+
+  noise: f32,
+
+  // Las grabaciones de un rango grabado, y el hilo que las está leyendo
+  samples: Option<Arc<SampleSet>>,
+  loading: Option<Receiver<Arc<SampleSet>>>,
+
+  /// El espectro de un rango con espectro, ver additive.rs
+  spectrum: Option<Spectrum>,
 }
 
-impl Default for Pipe {
-  fn default () -> Pipe {
+impl Pipe {
+  pub fn new (def: &RankDef, seed: u32) -> Pipe {
     Pipe {
-      harm: 1.0,
-      gain: 0.0,
+      gain: def.gain,
+      harm: footage_harm(def.footage),
+      breaks: def.breaks.iter()
+        .map(|b| (b.note, footage_harm(b.footage)))
+        .collect(),
+      voicing: def.voicing,
+
+      color: def.color,
+      color_key: color_key(def.color),
+      form: key_form(color_key(def.color)),
+      forms: None,
+      kind: def.kind,
+
+      attack: def.attack,
+      release: def.release,
 
-      //color: 0.5,
-      attack: 0.0,
-      release: 0.0,
+      chiff: def.chiff,
+      chiff_len: def.chiff_len,
+      chiff_scale: 1.0,
+      chiff_len_scale: 1.0,
 
-      form: Form::Sine,
+      scale: def.scale,
+      detune: def.detune,
+      layout: def.layout,
 
-      a_delta: 0.0,
-      r_delta: 0.0,
+      seed: seed,
+
+      noise: 0.0,
+
+      samples: None,
+      loading: match def.kind {
+        Kind::Sampled(sampled) => sampled.source()
+          .map(|source| SampleSet::load_async(source, sampled.path.to_string())),
+        _ => None
+      },
+
+      spectrum: match def.kind {
+        Kind::Additive(spectrum) => Some(Spectrum::new(spectrum)),
+        _ => None
+      },
     }
   }
-}
 
-impl Pipe {
+  /// Un rango grabado cuyos archivos no vienen de la definición.
+  pub fn with_source (def: &RankDef, seed: u32, source: Source, name: String) -> Pipe {
+    let mut pipe = Pipe::new(def, seed);
+    pipe.loading = Some(SampleSet::load_async(source, name));
+    pipe
+  }
+
+  /// Revisa si ya terminaron de leerse las grabaciones o de calcularse
+  /// las tablas del espectro. No bloquea, así que se puede llamar desde
+  /// el hilo de audio.
+  pub fn poll (&mut self) {
+    if let Some(ref mut spectrum) = self.spectrum { spectrum.poll(); }
 
-  pub fn set_color (&mut self, color: f32) {
-    self.form = match color {
-      x if x > 0.5 => Form::Warm(x-0.5),
-      x if x < 0.5 => Form::Cold(0.5-x),
-      _ => Form::Sine
+    let result = match self.loading {
+      Some(ref rx) => Some(rx.try_recv()),
+      None => None
     };
+
+    match result {
+      Some(Ok(set)) => { self.samples = Some(set); self.loading = None; },
+      // El hilo terminó sin mandar nada, ya reportó el error
+      Some(Err(TryRecvError::Disconnected)) => self.loading = None,
+      _ => {}
+    }
   }
 
-  pub fn calc_params (&mut self, fs: f32) {
-    let a = lerp(MIN_ATTACK, MAX_ATTACK, self.attack);
-    self.a_delta = 1.0 / (a*fs);
+  /// El color de todo el órgano, de 0 a 1, que se suma al del rango. En
+  /// 0.5 suena como fue entonado.
+  pub fn set_color (&mut self, value: f32, cache: &mut FormCache) {
+    let key = color_key(self.color + value - 0.5);
+    if key != self.color_key {
+      self.color_key = key;
+      self.form = key_form(key);
+      // Las tablas viejas no sirven, mientras llegan las nuevas se
+      // calcula la forma en cada sample.
+      self.forms = None;
+    }
+    self.update_forms(cache);
+  }
 
-    let r = lerp(MIN_RELEASE, MAX_RELEASE, self.release);
-    self.r_delta = db2amp(-20.0).powf(1.0 / (r*fs));
+  /// Toma las tablas de la forma si ya están calculadas, y si no las pide.
+  pub fn update_forms (&mut self, cache: &mut FormCache) {
+    if self.forms.is_some() { return; }
+    self.forms = match (self.kind, self.form) {
+      (Kind::Flue, Form::Warm(_)) | (Kind::Flue, Form::Cold(_)) => cache.get(self.color_key),
+      _ => None
+    };
+  }
+
+  pub fn set_noise (&mut self, value: f32) { self.noise = value-0.5; }
+
+  /// El espectro, si el rango tiene.
+  pub fn spectrum (&self) -> Option<&Spectrum> { self.spectrum.as_ref() }
+
+  /// Las amplitudes de los armónicos, si el rango tiene espectro.
+  pub fn harmonics (&self) -> Option<&[f32]> {
+    self.spectrum.as_ref().map(|spectrum| spectrum.harmonics())
+  }
+
+  /// Cambia un armónico del espectro, si el rango tiene.
+  pub fn set_harmonic (&mut self, index: usize, value: f32) {
+    if let Some(ref mut spectrum) = self.spectrum { spectrum.set_harmonic(index, value); }
+  }
+
+  /// Qué tan fuerte suena el rango, para ordenar el crescendo. Es el
+  /// múltiplo de la fundamental en el C central, y el volumen pesado por
+  /// el color. None si no debe entrar en el crescendo, como los rangos
+  /// ondulantes, y las lengüetas devuelven true para ir al final.
+  pub fn loudness (&self) -> Option<(f32, f32, bool)> {
+    if self.detune != 0.0 { return None; }
+    let color = match self.form {
+      Form::Warm(x) => 0.5 + x,
+      Form::Cold(x) => 0.5 - x,
+      Form::Sine => 0.5,
+    };
+    let reed = match self.kind { Kind::Reed(_) => true, _ => false };
+    Some((self.harm_at(60), self.gain * (0.5 + color), reed))
+  }
+
+  /// Prepara un oscilador de este rango para una frecuencia de muestreo.
+  pub fn init (&self, osc: &mut Osc, fs: f32) {
+    match self.kind {
+      Kind::Model(_) => flue::init(osc, fs),
+      _ => {}
+    }
   }
 
-  pub fn clock (&self, osc: &mut Osc) -> f32 {
+  /// El volumen del chiff respecto al del rango, de 0 a 1. En 0.5 suena
+  /// como fue entonado, y en 1 al doble.
+  pub fn set_chiff (&mut self, value: f32) { self.chiff_scale = 2.0 * value; }
+
+  /// La duración del chiff respecto a la del rango, de 0 a 1. En 0.5
+  /// dura lo que fue entonado.
+  pub fn set_chiff_len (&mut self, value: f32) {
+    self.chiff_len_scale = CHIFF_LEN_RANGE.powf(2.0*value - 1.0);
+  }
+
+  fn chiff (&self, osc: &mut Osc) -> f32 {
+    // 0.0001 amplitud = -80dB
+    if osc.chiff < 0.0001 { return 0.0; }
+
+    let (_, band, _) = osc.chiff_filter.clock(self.noise);
+    let sine = sin01(osc.chiff_phase);
+
+    osc.chiff_phase += osc.delta * CHIFF_RATIO;
+    if osc.chiff_phase >= 1.0 { osc.chiff_phase -= 1.0; }
+
+    let sample = (sine*CHIFF_SINE + band*CHIFF_Q*CHIFF_NOISE) * osc.chiff;
+    osc.chiff *= osc.chiff_decay;
+    sample
+  }
+
+  pub fn clock (&self, osc: &mut Osc, wind: &Wind) -> f32 {
     match osc.state {
       State::Attack => {
-        osc.vol += self.a_delta;
+        osc.vol += osc.a_delta;
         if osc.vol >= 1.0 {
           osc.vol = 1.0;
           osc.state = State::Sustain;
         }
       },
       State::Release => {
-        osc.vol *= self.r_delta;
-        if osc.vol*self.gain < db2amp(-80.0) {
+        osc.vol *= osc.r_delta;
+        if osc.vol*osc.gain*self.gain < db2amp(-80.0) {
           osc.vol = 0.0;
           osc.state = State::Off;
         }
@@ -142,25 +1135,79 @@ impl Pipe {
     // No puedo dejar de calcular el envelope porque el gain puede
     // cambiar en la mitad de una nota, pero lo que sí puedo dejar
     // de calcular es el sample.
-    if self.gain == 0.0 { return 0.0; }
+    if self.gain == 0.0 { osc.chiff = 0.0; return 0.0; }
+
+    let chiff = self.chiff(osc);
 
-    let bright = osc.bright * (0.1*osc.vol + 0.9);
+    // En el modelo físico el envelope es la presión del
+    // viento, y ya está incluido en el sample.
+    let sample = match self.kind {
+      Kind::Flue => self.flue(osc, wind) * osc.vol * wind.amp,
+      Kind::Reed(def) => reed::clock(def, osc, wind) * osc.vol * wind.amp,
+      Kind::Model(def) => flue::clock(def, osc, self.noise, wind),
+      Kind::Sampled(_) => sampled::clock(osc, wind) * osc.vol * wind.amp,
+      Kind::Additive(_) => match self.spectrum {
+        Some(ref spectrum) => additive::clock(spectrum.tables(), osc, wind) * osc.vol * wind.amp,
+        None => 0.0,
+      },
+    };
 
+    (sample + chiff) * osc.gain * self.gain
+  }
+
+  fn flue (&self, osc: &mut Osc, wind: &Wind) -> f32 {
     let ph = osc.phase;
 
-    let sample = match self.form {
-      Form::Warm(x) => sin01(tens01(ph, x*WARM*bright)),
-      Form::Cold(x) => tensinv(sin01(ph), x*COLD*bright),
-      Form::Sine => sin01(ph),
+    let sample = match (self.form, &self.forms) {
+      (Form::Sine, _) => sin01(ph),
+      // El brillo de abajo, pero en bandas
+      (_, &Some(ref forms)) => {
+        let modulation = log2_near_one(0.1*osc.vol + 0.9) + wind.bright_log2;
+        forms.read(osc.bright_band + modulation * BANDS_PER_OCTAVE, osc.table, ph)
+      },
+      // Mientras se calculan las tablas
+      (form, &None) => {
+        let bright = osc.bright * (0.1*osc.vol + 0.9) * wind.bright;
+        shape(form, ph, bright)
+      },
     };
 
-    osc.phase += osc.delta * self.harm;
+    osc.phase += osc.delta * wind.pitch;
     if osc.phase >= 1.0 { osc.phase -= 1.0; }
 
-    sample * osc.vol * self.gain
+    sample
+  }
+
+  /// El múltiplo de la fundamental con el que suena el tubo de una nota.
+  fn harm_at (&self, note: u8) -> f32 {
+    let mut harm = self.harm;
+    for &(from, h) in self.breaks.iter() {
+      if note >= from { harm = h; }
+    }
+    harm
+  }
+
+  /// Las curvas de entonación en una nota.
+  fn voicing_at (&self, note: u8) -> Voicing {
+    let points = self.voicing;
+    let next = match points.iter().position(|v| v.note > note) {
+      Some(0) => return points[0],
+      Some(i) => i,
+      None => return points.last().cloned().unwrap_or(NEUTRAL),
+    };
+
+    let (a, b) = (points[next-1], points[next]);
+    let t = (note - a.note) as f32 / (b.note - a.note) as f32;
+    Voicing {
+      note: note,
+      gain: lerp(a.gain, b.gain, t),
+      bright: lerp(a.bright, b.bright, t),
+      speech: lerp(a.speech, b.speech, t),
+      chiff: lerp(a.chiff, b.chiff, t),
+    }
   }
 
-  pub fn note_on (&self, osc: &mut Osc, freq: f32, fs: f32) {
+  pub fn note_on (&self, osc: &mut Osc, note: u8, freq: f32, fs: f32) {
     // Color indica el brillo con el que suena el tubo que se activa cuando se
     // toca C3 (la nota más baja en un órgano real). La frecuencia que me dan
     // como parámetro asume el tono fundamental (lo cual no es inconveniente
@@ -172,17 +1219,52 @@ impl Pipe {
     // a 64Hz, pero la frecuencia que recibo es con A5 a 440Hz, para que suene
     // afinado con el resto de los instrumentos.
 
-    osc.phase = 0.0;
+    // Cada tubo tiene su propia desafinación y fase inicial, que dependen
+    // de la semilla del rango y de la nota.
+    let key = (self.seed << 8) | note as u32;
+    let cents = self.detune + RANDOM_CENTS * (2.0*hash01(key) - 1.0);
+
+    osc.phase = hash01(key ^ 0x5A5A_0000);
+    osc.pan = self.layout.position(note);
     osc.vol = 0.0;
-    osc.delta = freq/fs;
-    osc.bright = 64.0/freq;
+    let harm = self.harm_at(note);
+    if harm == 0.0 { osc.release(); return; }
+
+    let voicing = self.voicing_at(note);
+
+    osc.delta = freq*harm*2_f32.powf(cents/1200.0)/fs;
+    osc.bright = 64.0/freq * voicing.bright;
+    osc.bright_band = FormTables::band(osc.bright);
     osc.state = State::Attack;
-  }
+    osc.a_delta = 1.0 / (self.attack*voicing.speech*fs);
+    osc.r_delta = db2amp(-20.0).powf(1.0 / (self.release*voicing.speech*fs));
+
+    let pitch = freq * harm;
+    osc.table = wavetable::table_for(pitch, fs);
+    let scale = CHIFF_REF / pitch;
+    let len = lerp(MIN_CHIFF, MAX_CHIFF, self.chiff_len) * self.chiff_len_scale
+      * scale.powf(CHIFF_LEN_EXP);
+    osc.chiff = self.chiff * self.chiff_scale * voicing.chiff * scale.powf(-CHIFF_VOL_EXP);
+    osc.chiff_decay = db2amp(-20.0).powf(1.0 / (len*fs));
+    osc.chiff_phase = 0.0;
+    // Una voz reusada no debe arrastrar el ruido de la nota anterior
+    osc.chiff_filter.reset();
+    osc.chiff_filter.set_params(CHIFF_Q, pitch*CHIFF_RATIO, fs);
 
-  pub fn set_harm(&mut self, h: f32) {
-    let high = (harmonics.len() - 1) as f32;
-    let i = (h*high).min(high) as usize;
-    self.harm = harmonics[i];
+    // La escala es relativa al tubo de SCALE_REF, que es más o menos el
+    // más grave de un registro de 8' (ver arriba).
+    osc.gain = db2amp(self.scale * (pitch/SCALE_REF).log2() + voicing.gain);
+
+    match self.kind {
+      Kind::Reed(def) => reed::note_on(def, osc, fs),
+      Kind::Model(def) => flue::note_on(def, osc, pitch, fs),
+      Kind::Sampled(_) => match self.samples {
+        Some(ref set) => sampled::note_on(set, osc, note, fs),
+        // Todavía no se lee, o no se pudo leer
+        None => { osc.state = State::Off; }
+      },
+      Kind::Additive(_) | Kind::Flue => {}
+    }
   }
 
   pub fn set_gain(&mut self, g: f32) {
@@ -191,6 +1273,45 @@ impl Pipe {
 }
 
 impl Osc {
-  pub fn release (&mut self) { self.state = State::Release; }
+  /// Un oscilador apagado sigue apagado, no tiene nada que soltar.
+  pub fn release (&mut self) {
+    if self.state != State::Off { self.state = State::Release; }
+  }
+  /// Apaga el oscilador de golpe, sin release.
+  pub fn reset (&mut self) {
+    self.state = State::Off;
+    self.vol = 0.0;
+    self.chiff = 0.0;
+  }
   pub fn is_active (&self) -> bool { self.state != State::Off }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// La tabla más grande, con todos los armónicos.
+  const ALL_HARMONICS: usize = 20;
+
+  fn max_error (tables: &FormTables, form: Form, band: f32) -> f32 {
+    let bright = BRIGHT_MIN * 2_f32.powf(band / BANDS_PER_OCTAVE);
+    (0..1000).map(|i| {
+      let ph = i as f32 / 1000.0;
+      (tables.read(band, ALL_HARMONICS, ph) - shape(form, ph, bright)).abs()
+    }).fold(0.0, f32::max)
+  }
+
+  #[test]
+  fn tables_follow_shape () {
+    for &form in [Form::Warm(0.1), Form::Warm(0.5), Form::Cold(0.1), Form::Cold(0.5)].iter() {
+      let tables = FormTables::new(form);
+      // En las bandas la tabla solo pierde los armónicos más débiles, y
+      // entre dos bandas también se nota la mezcla.
+      for band in 0..BAND_COUNT {
+        assert!(max_error(&tables, form, band as f32) < 0.01);
+      }
+      for band in 0..BAND_COUNT-1 {
+        assert!(max_error(&tables, form, band as f32 + 0.5) < 0.02);
+      }
+    }
+  }
+}
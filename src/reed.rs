@@ -0,0 +1,128 @@
+use helpers::*;
+use hammond::StateVariable;
+use pipe::Osc;
+use wind::Wind;
+
+/*
+
+Los tubos de lengüeta (reeds) no suenan como una forma de onda suave sino
+como una lengüeta que golpea y corta el aire, y eso da un espectro muy
+brillante, casi un diente de sierra o un pulso. El resonador (la campana
+del tubo) le da su color con unas pocas formantes de frecuencia fija, que
+no siguen a la nota. Por eso aquí la fuente es un saw/pulso limitado en
+banda (polyBLEP), filtrado por un banco de pasabandas en paralelo.
+
+Además la lengüeta tarda un momento en estabilizarse, y la nota empieza un
+poco desafinada hacia abajo antes de asentarse.
+
+*/
+
+pub const FORMANT_COUNT: usize = 3;
+
+#[derive(Clone, Copy)]
+pub struct Formant {
+  /// Frecuencia central en Hz
+  pub freq: f32,
+  /// Amortiguamiento, mientras más bajo más estrecha es la formante
+  pub q: f32,
+  pub gain: f32,
+}
+
+pub struct ReedDef {
+  /// Mezcla entre saw (0) y pulso (1)
+  pub pulse: f32,
+  /// Ancho del pulso, 0.5 es cuadrada y solo tiene armónicos impares
+  pub width: f32,
+
+  /// Cuánto de la fuente pasa sin filtrar
+  pub dry: f32,
+  pub formants: [Formant; FORMANT_COUNT],
+
+  /// Desafinación con la que empieza la nota, en cents
+  pub settle: f32,
+  /// Tiempo que tarda la afinación en llegar a -20dB de la desafinación
+  /// inicial, en segundos
+  pub settle_time: f32,
+}
+
+pub const TRUMPET: ReedDef = ReedDef {
+  pulse: 0.0, width: 0.5,
+  dry: 0.5,
+  formants: [
+    Formant { freq: 1200.0, q: 0.7, gain: 1.0 },
+    Formant { freq: 2500.0, q: 0.8, gain: 0.6 },
+    Formant { freq: 4000.0, q: 1.0, gain: 0.3 },
+  ],
+  settle: -30.0, settle_time: 0.02,
+};
+
+pub const OBOE: ReedDef = ReedDef {
+  pulse: 0.6, width: 0.3,
+  dry: 0.2,
+  formants: [
+    Formant { freq: 1000.0, q: 0.3, gain: 1.2 },
+    Formant { freq: 2800.0, q: 0.4, gain: 0.5 },
+    Formant { freq: 3800.0, q: 0.5, gain: 0.2 },
+  ],
+  settle: -20.0, settle_time: 0.03,
+};
+
+// Una vocal "a" nasal, con un pulso cuadrado como la lengüeta corta
+// de los regales.
+pub const VOX_HUMANA: ReedDef = ReedDef {
+  pulse: 1.0, width: 0.5,
+  dry: 0.1,
+  formants: [
+    Formant { freq: 600.0, q: 0.3, gain: 1.0 },
+    Formant { freq: 1000.0, q: 0.3, gain: 0.8 },
+    Formant { freq: 2400.0, q: 0.5, gain: 0.3 },
+  ],
+  settle: -15.0, settle_time: 0.03,
+};
+
+/// Corrección polyBLEP para la discontinuidad de una onda en la fase 0.
+fn blep (t: f32, dt: f32) -> f32 {
+  if t < dt {
+    let t = t/dt;
+    t+t - t*t - 1.0
+  } else if t > 1.0 - dt {
+    let t = (t - 1.0)/dt;
+    t*t + t+t + 1.0
+  } else { 0.0 }
+}
+
+/// Diente de sierra limitado en banda, con rango [-1, 1]
+fn saw (ph: f32, dt: f32) -> f32 {
+  2.0*ph - 1.0 - blep(ph, dt)
+}
+
+pub fn note_on (def: &ReedDef, osc: &mut Osc, fs: f32) {
+  osc.bend = 2_f32.powf(def.settle/1200.0) - 1.0;
+  osc.bend_decay = db2amp(-20.0).powf(1.0 / (def.settle_time*fs));
+
+  for (filter, formant) in osc.formants.iter_mut().zip(def.formants.iter()) {
+    filter.set_params(formant.q, formant.freq, fs);
+  }
+}
+
+pub fn clock (def: &ReedDef, osc: &mut Osc, wind: &Wind) -> f32 {
+  let dt = osc.delta * (1.0 + osc.bend) * wind.pitch;
+  osc.bend *= osc.bend_decay;
+
+  let ph = osc.phase;
+  let ph2 = mod1(ph + def.width);
+  let s = saw(ph, dt);
+  let pulse = s - saw(ph2, dt);
+  let source = lerp(s, pulse, def.pulse);
+
+  osc.phase += dt;
+  if osc.phase >= 1.0 { osc.phase -= 1.0; }
+
+  let mut sample = source * def.dry;
+  for (filter, formant) in osc.formants.iter_mut().zip(def.formants.iter()) {
+    // La banda tiene ganancia 1/q en el centro
+    let (_, band, _) = filter.clock(source);
+    sample += band * formant.q * formant.gain;
+  }
+  sample
+}
@@ -0,0 +1,645 @@
+use std::io::{self, Read, Write};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::fmt;
+
+use helpers::*;
+use pipe::{Osc, State};
+use wind::Wind;
+use sfz::{self, LoopMode};
+
+/*
+
+Rangos de tubos grabados. Cada tubo es un archivo WAV con tres partes: el
+ataque, un loop que se repite mientras la tecla está presionada, y la
+cola del release, que es todo lo que hay después del loop. Los puntos del
+loop vienen del chunk `smpl` del archivo, que es donde los ponen los
+editores de samples. Sin loop, el archivo se toca una sola vez.
+
+Las notas que no tienen grabación usan la grabación más cercana, con el
+tono cambiado. Para eso se interpola con un sinc con ventana, que suena
+mucho más limpio que una interpolación lineal.
+
+Leer los archivos tarda, así que se hace en otro hilo y el rango no suena
+hasta que termina. Si algo falla el rango se queda callado y el error se
+reporta por stderr, nunca se cae el plugin.
+
+*/
+
+/// Carpeta base de las grabaciones, igual que las imágenes del editor.
+const SAMPLES_DIR: &'static str = "assets/samples";
+
+// Sinc con ventana de Blackman, con SINC_TAPS puntos y una tabla con
+// SINC_PHASES fases entre cada sample.
+const SINC_TAPS: usize = 8;
+const SINC_PHASES: usize = 256;
+
+/// Frecuencia de corte del sinc, relativa a Nyquist. Un poco menos de 1
+/// para que la ventana tenga dónde caer.
+const SINC_CUTOFF: f64 = 0.9;
+
+// Al subir el tono la grabación se lee más rápido, y lo que pasa de
+// Nyquist vuelve como aliasing. Por eso hay un sinc por cada cuarto de
+// octava de subida, con el corte bajado en esa proporción y más puntos
+// para que siga igual de empinado. Más arriba de SINC_OCTAVES se usa el
+// último.
+const SINC_STEPS_PER_OCTAVE: usize = 4;
+const SINC_OCTAVES: usize = 3;
+
+/// Duración del cruce entre el loop y un release aparte, en segundos.
+const XFADE_S: f32 = 0.03;
+
+/// Un rango grabado, los archivos están en SAMPLES_DIR/path. Cada archivo
+/// es una nota, y la nota es la del chunk smpl o, si no tiene, el número
+/// midi en el nombre del archivo (por ejemplo 060.wav). Si path es un
+/// archivo .sfz, las notas son las del SFZ.
+pub struct SampledDef {
+  pub path: &'static str,
+}
+
+pub const PEDAL_BASS: SampledDef = SampledDef { path: "pedal-bass" };
+pub const HARMONIUM: SampledDef = SampledDef { path: "harmonium/harmonium.sfz" };
+
+#[derive(Debug)]
+pub enum LoadError {
+  Io(PathBuf, io::Error),
+  /// El archivo no es un WAV que se pueda leer
+  Format(PathBuf, &'static str),
+  /// La carpeta no tiene ninguna grabación
+  Empty(PathBuf),
+}
+
+impl fmt::Display for LoadError {
+  fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      LoadError::Io(ref path, ref err) => write!(f, "{}: {}", path.display(), err),
+      LoadError::Format(ref path, msg) => write!(f, "{}: {}", path.display(), msg),
+      LoadError::Empty(ref path) => write!(f, "{}: no recordings found", path.display()),
+    }
+  }
+}
+
+/// Envelope de volumen de una grabación, como los opcodes ampeg_* de SFZ.
+/// Los tiempos están en segundos y sustain va de 0 a 1. Sin envelope la
+/// grabación suena tal cual, con su propio ataque y su propia cola.
+#[derive(Clone, Copy)]
+pub struct Envelope {
+  pub attack: f32,
+  pub hold: f32,
+  pub decay: f32,
+  pub sustain: f32,
+  pub release: f32,
+}
+
+/// Un tubo grabado, en mono.
+pub struct Recording {
+  pub data: Vec<f32>,
+  pub sample_rate: f32,
+
+  /// Frecuencia a la que suena la grabación
+  pub freq: f32,
+  pub gain: f32,
+
+  /// El loop es data[loop_start .. loop_end], si hay
+  pub looped: Option<(usize, usize)>,
+  /// Si el loop sigue después de soltar la tecla, hasta que termine el
+  /// envelope.
+  pub loop_release: bool,
+
+  pub envelope: Option<Envelope>,
+}
+
+/// Todas las grabaciones de un rango, y la tabla del sinc.
+pub struct SampleSet {
+  recordings: Vec<Recording>,
+
+  /// La grabación que usa cada nota midi
+  notes: Vec<Option<usize>>,
+  /// La grabación que suena al soltar cada nota, si hay una aparte
+  releases: Vec<Option<usize>>,
+  /// Si las notas son teclas con su propio tubo, o tonos
+  keyed: bool,
+
+  /// Los sinc de cada velocidad de lectura, ver sinc_for
+  sinc: Vec<Sinc>,
+}
+
+fn u16_at (b: &[u8], i: usize) -> u16 { b[i] as u16 | (b[i+1] as u16) << 8 }
+fn u32_at (b: &[u8], i: usize) -> u32 { u16_at(b, i) as u32 | (u16_at(b, i+2) as u32) << 16 }
+
+pub fn note_freq (note: f32) -> f32 { 440.0 * 2_f32.powf((note - 69.0) / 12.0) }
+
+/// Un WAV leído, con cada canal por separado.
+pub struct Wav {
+  pub channels: Vec<Vec<f32>>,
+  pub sample_rate: f32,
+  /// La nota del chunk smpl, si hay
+  pub note: Option<f32>,
+  /// El primer loop del chunk smpl, si hay
+  pub looped: Option<(usize, usize)>,
+}
+
+/// Lee un WAV sin mezclar los canales.
+pub fn read_wav_channels (path: &Path) -> Result<Wav, LoadError> {
+  let format = |msg| LoadError::Format(path.to_path_buf(), msg);
+
+  let mut bytes = Vec::new();
+  File::open(path)
+    .and_then(|mut file| file.read_to_end(&mut bytes))
+    .map_err(|err| LoadError::Io(path.to_path_buf(), err))?;
+
+  if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+    return Err(format("not a WAV file"));
+  }
+
+  let mut fmt = None;
+  let mut data = None;
+  let mut note = None;
+  let mut looped = None;
+
+  let mut i = 12;
+  while i + 8 <= bytes.len() {
+    let id = &bytes[i .. i+4];
+    let size = u32_at(&bytes, i+4) as usize;
+    let start = i + 8;
+    let end = (start + size).min(bytes.len());
+    let chunk = &bytes[start .. end];
+
+    match id {
+      b"fmt " if chunk.len() >= 16 => fmt = Some(chunk),
+      b"data" => data = Some(chunk),
+      b"smpl" if chunk.len() >= 36 => {
+        // Nota midi y fracción de semitono (en 1/2^32)
+        let unity = u32_at(chunk, 12) as f32;
+        let fraction = u32_at(chunk, 16) as f32 / 4294967296.0;
+        note = Some(unity + fraction);
+
+        // Solo se usa el primer loop
+        let loops = u32_at(chunk, 28);
+        if loops > 0 && chunk.len() >= 36 + 24 {
+          let loop_start = u32_at(chunk, 36 + 8) as usize;
+          let loop_end = u32_at(chunk, 36 + 12) as usize;
+          looped = Some((loop_start, loop_end + 1));
+        }
+      },
+      _ => {}
+    }
+
+    // Los chunks están alineados a 2 bytes
+    i = start + size + (size & 1);
+  }
+
+  let fmt = fmt.ok_or(format("missing fmt chunk"))?;
+  let data = data.ok_or(format("missing data chunk"))?;
+
+  let mut tag = u16_at(fmt, 0);
+  let channels = u16_at(fmt, 2) as usize;
+  let sample_rate = u32_at(fmt, 4) as f32;
+  let bits = u16_at(fmt, 14) as usize;
+
+  // WAVE_FORMAT_EXTENSIBLE tiene el formato real en el subformato
+  if tag == 0xFFFE && fmt.len() >= 26 { tag = u16_at(fmt, 24); }
+
+  if channels == 0 || sample_rate <= 0.0 { return Err(format("invalid fmt chunk")); }
+
+  let bytes_per = bits / 8;
+  let decode: fn(&[u8]) -> f32 = match (tag, bits) {
+    (1, 8) => |b| (b[0] as f32 - 128.0) / 128.0,
+    (1, 16) => |b| u16_at(b, 0) as i16 as f32 / 32768.0,
+    (1, 24) => |b| ((u32_at(&[0, b[0], b[1], b[2]], 0) as i32) >> 8) as f32 / 8388608.0,
+    (1, 32) => |b| u32_at(b, 0) as i32 as f32 / 2147483648.0,
+    (3, 32) => |b| unsafe { ::std::mem::transmute::<u32, f32>(u32_at(b, 0)) },
+    _ => return Err(format("unsupported sample format")),
+  };
+
+  let frame = bytes_per * channels;
+  let mut samples = vec![Vec::new(); channels];
+  for f in data.chunks(frame).filter(|f| f.len() == frame) {
+    for (channel, b) in samples.iter_mut().zip(f.chunks(bytes_per)) {
+      channel.push(decode(b));
+    }
+  }
+
+  let len = samples[0].len();
+  if len == 0 { return Err(format("no audio data")); }
+
+  // Un loop fuera del archivo no sirve de nada
+  let looped = looped.and_then(|(a, b)| {
+    if a < b && b <= len { Some((a, b)) } else { None }
+  });
+
+  Ok(Wav {
+    channels: samples,
+    sample_rate: sample_rate,
+    note: note,
+    looped: looped,
+  })
+}
+
+/// Lee un WAV. Devuelve la grabación y la nota del chunk smpl, si hay.
+pub fn read_wav (path: &Path) -> Result<(Recording, Option<f32>), LoadError> {
+  let wav = read_wav_channels(path)?;
+
+  // Todos los canales se mezclan en uno
+  let count = wav.channels.len() as f32;
+  let samples = (0 .. wav.channels[0].len())
+    .map(|i| wav.channels.iter().map(|c| c[i]).sum::<f32>() / count)
+    .collect();
+
+  let recording = Recording {
+    data: samples,
+    sample_rate: wav.sample_rate,
+    freq: note_freq(wav.note.unwrap_or(69.0)),
+    gain: 1.0,
+    looped: wav.looped,
+    loop_release: false,
+    envelope: None,
+  };
+  Ok((recording, wav.note))
+}
+
+/// Un sinc para leer una grabación a cierta velocidad, ver SINC_OCTAVES.
+struct Sinc {
+  taps: usize,
+  coefs: Vec<f32>,
+}
+
+impl Sinc {
+  /// El sinc para leer una grabación a ratio samples por sample, con
+  /// ratio de 1 en adelante.
+  fn new (ratio: f64) -> Sinc {
+    use std::f64::consts::PI;
+
+    let cutoff = SINC_CUTOFF / ratio;
+    // Siempre par, para que haya tantos puntos antes como después
+    let taps = 2 * (SINC_TAPS as f64 * ratio / 2.0).ceil() as usize;
+    let half = (taps / 2) as f64;
+    let mut coefs = Vec::with_capacity(taps * SINC_PHASES);
+
+    for phase in 0..SINC_PHASES {
+      let frac = phase as f64 / SINC_PHASES as f64;
+      for tap in 0..taps {
+        // Distancia del punto al instante que se interpola
+        let x = tap as f64 - (half - 1.0) - frac;
+        let sinc = if x == 0.0 { 1.0 } else {
+          let y = PI * x * cutoff;
+          y.sin() / y
+        };
+        let w = (x / half + 1.0) / 2.0;
+        let window = 0.42 - 0.5 * (2.0*PI*w).cos() + 0.08 * (4.0*PI*w).cos();
+        coefs.push((sinc * window * cutoff) as f32);
+      }
+    }
+    Sinc { taps: taps, coefs: coefs }
+  }
+}
+
+fn sinc_tables () -> Vec<Sinc> {
+  (0 .. SINC_OCTAVES * SINC_STEPS_PER_OCTAVE + 1).map(|i| {
+    Sinc::new(2_f64.powf(i as f64 / SINC_STEPS_PER_OCTAVE as f64))
+  }).collect()
+}
+
+/// El sinc que corresponde a una velocidad de lectura. Se redondea hacia
+/// arriba, para que el corte nunca quede por encima de Nyquist.
+fn sinc_for (step: f64) -> usize {
+  let i = (step.max(1.0).log2() * SINC_STEPS_PER_OCTAVE as f64 - 1e-6).ceil();
+  (i.max(0.0) as usize).min(SINC_OCTAVES * SINC_STEPS_PER_OCTAVE)
+}
+
+/// Los archivos de un tubo: el ataque con su loop, y el release aparte.
+/// Sin release, el release es lo que está después del loop en el ataque.
+#[derive(Clone)]
+pub struct PipeFiles {
+  pub key: u8,
+  pub attack: PathBuf,
+  pub release: Option<PathBuf>,
+}
+
+/// De dónde salen las grabaciones de un rango.
+pub enum Source {
+  /// Una carpeta con una grabación por nota, las notas que faltan se
+  /// sacan cambiando el tono de las vecinas.
+  Dir(PathBuf),
+  /// Un tubo por tecla, como en los ODF de GrandOrgue. Cada tubo suena
+  /// como fue grabado, y las teclas sin tubo no suenan.
+  Pipes(Vec<PipeFiles>),
+  /// Un instrumento SFZ, ver el módulo sfz.
+  Sfz(PathBuf),
+}
+
+impl SampledDef {
+  /// Los rangos sin carpeta reciben sus archivos de otro lado. Si path
+  /// termina en .sfz es un instrumento SFZ y no una carpeta.
+  pub fn source (&self) -> Option<Source> {
+    if self.path.is_empty() { return None; }
+
+    let path = Path::new(SAMPLES_DIR).join(self.path);
+    let is_sfz = path.extension()
+      .map(|ext| ext.to_string_lossy().to_lowercase() == "sfz")
+      .unwrap_or(false);
+    if is_sfz { Some(Source::Sfz(path)) } else { Some(Source::Dir(path)) }
+  }
+
+  /// Si los archivos del rango están en el disco. Los rangos sin carpeta
+  /// no tienen cómo saberlo.
+  pub fn exists (&self) -> bool {
+    !self.path.is_empty() && Path::new(SAMPLES_DIR).join(self.path).exists()
+  }
+}
+
+/// Un rango cuyos archivos no vienen de una carpeta, ver Pipe::with_source.
+pub const EXTERNAL: SampledDef = SampledDef { path: "" };
+
+impl SampleSet {
+  /// Lee todas las grabaciones de una carpeta.
+  fn load_dir (dir: &Path) -> Result<SampleSet, LoadError> {
+    let entries = fs::read_dir(dir).map_err(|err| LoadError::Io(dir.to_path_buf(), err))?;
+
+    let mut recordings = Vec::new();
+    for entry in entries {
+      let path = entry.map_err(|err| LoadError::Io(dir.to_path_buf(), err))?.path();
+      let is_wav = path.extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase() == "wav")
+        .unwrap_or(false);
+      if !is_wav { continue; }
+
+      let (mut recording, note) = read_wav(&path)?;
+      if note.is_none() {
+        let stem = path.file_stem().map(|s| s.to_string_lossy().into_owned());
+        match stem.and_then(|s| s.parse::<u8>().ok()) {
+          Some(note) => recording.freq = note_freq(note as f32),
+          None => return Err(LoadError::Format(path, "no smpl chunk and no midi note in the name")),
+        }
+      }
+      recordings.push(recording);
+    }
+
+    if recordings.is_empty() { return Err(LoadError::Empty(dir.to_path_buf())); }
+
+    // Cada nota usa la grabación más cercana en tono
+    let notes = (0..128).map(|note| {
+      let freq = note_freq(note as f32);
+      let mut best = 0;
+      for (i, rec) in recordings.iter().enumerate() {
+        let d = (rec.freq / freq).log2().abs();
+        if d < (recordings[best].freq / freq).log2().abs() { best = i; }
+      }
+      Some(best)
+    }).collect();
+
+    Ok(SampleSet {
+      recordings: recordings,
+      notes: notes,
+      releases: vec![None; 128],
+      keyed: false,
+      sinc: sinc_tables(),
+    })
+  }
+
+  fn load_pipes (pipes: &[PipeFiles]) -> Result<SampleSet, LoadError> {
+    let mut recordings = Vec::new();
+    let mut notes = vec![None; 128];
+    let mut releases = vec![None; 128];
+
+    for pipe in pipes.iter() {
+      let key = pipe.key as usize & 0x7F;
+
+      let (recording, _) = read_wav(&pipe.attack)?;
+      notes[key] = Some(recordings.len());
+      recordings.push(recording);
+
+      if let Some(ref path) = pipe.release {
+        let (release, _) = read_wav(path)?;
+        releases[key] = Some(recordings.len());
+        recordings.push(release);
+      }
+    }
+
+    Ok(SampleSet {
+      recordings: recordings,
+      notes: notes,
+      releases: releases,
+      keyed: true,
+      sinc: sinc_tables(),
+    })
+  }
+
+  /// Cada región de un SFZ es una grabación, y cada nota usa la última
+  /// región que la cubre.
+  fn load_sfz (path: &Path) -> Result<SampleSet, LoadError> {
+    let regions = sfz::load(path)?;
+
+    let mut recordings = Vec::new();
+    let mut notes = vec![None; 128];
+    let mut releases = vec![None; 128];
+
+    for region in regions.into_iter() {
+      let (mut recording, _) = read_wav(&region.sample)?;
+      recording.freq = note_freq(region.keycenter);
+      recording.gain = region.gain;
+      recording.envelope = Some(region.envelope);
+
+      match region.looped {
+        LoopMode::NoLoop => recording.looped = None,
+        LoopMode::Sustain(points) => recording.looped = points.or(recording.looped),
+        LoopMode::Continuous(points) => {
+          recording.looped = points.or(recording.looped);
+          recording.loop_release = true;
+        },
+      }
+      // Un loop fuera del archivo no sirve de nada
+      if let Some((a, b)) = recording.looped {
+        if a >= b || b > recording.data.len() { recording.looped = None; }
+      }
+
+      let table = if region.release { &mut releases } else { &mut notes };
+      for key in region.lokey .. region.hikey + 1 {
+        table[key as usize & 0x7F] = Some(recordings.len());
+      }
+      recordings.push(recording);
+    }
+
+    if recordings.is_empty() { return Err(LoadError::Empty(path.to_path_buf())); }
+
+    Ok(SampleSet {
+      recordings: recordings,
+      notes: notes,
+      releases: releases,
+      keyed: false,
+      sinc: sinc_tables(),
+    })
+  }
+
+  pub fn load (source: &Source) -> Result<SampleSet, LoadError> {
+    match *source {
+      Source::Dir(ref dir) => SampleSet::load_dir(dir),
+      Source::Pipes(ref pipes) => SampleSet::load_pipes(pipes),
+      Source::Sfz(ref path) => SampleSet::load_sfz(path),
+    }
+  }
+
+  /// Empieza a leer las grabaciones de un rango en otro hilo. Los errores
+  /// se reportan desde ese hilo, el rango simplemente no recibe nada.
+  pub fn load_async (source: Source, name: String) -> Receiver<Arc<SampleSet>> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+      match SampleSet::load(&source) {
+        Ok(set) => { let _ = tx.send(Arc::new(set)); },
+        Err(err) => report(&name, &err),
+      }
+    });
+    rx
+  }
+
+  /// Interpola una grabación en una posición con fracción, con el sinc
+  /// de índice sinc.
+  fn interp (&self, data: &[f32], pos: f64, sinc: usize) -> f32 {
+    let sinc = &self.sinc[sinc];
+    let i = pos.floor();
+    let phase = ((pos - i) * SINC_PHASES as f64) as usize;
+    let coefs = &sinc.coefs[phase * sinc.taps .. (phase+1) * sinc.taps];
+
+    let first = i as isize - (sinc.taps as isize / 2 - 1);
+    let mut sum = 0.0;
+    for (k, c) in coefs.iter().enumerate() {
+      let j = first + k as isize;
+      if j >= 0 && (j as usize) < data.len() {
+        sum += data[j as usize] * c;
+      }
+    }
+    sum
+  }
+}
+
+/// Reporta un error de carga. No hay otro lugar donde mostrarlo.
+pub fn report (name: &str, err: &LoadError) {
+  let _ = writeln!(&mut io::stderr(), "[organ] Sampled rank '{}' failed to load: {}", name, err);
+}
+
+pub fn note_on (set: &Arc<SampleSet>, osc: &mut Osc, note: u8, fs: f32) {
+  // La frecuencia real del tubo, con su desafinación, ya está en delta
+  let freq = osc.delta * fs;
+
+  let note = if set.keyed { note as usize & 0x7F } else {
+    (69.0 + 12.0 * (freq / 440.0).log2()).round().max(0.0).min(127.0) as usize
+  };
+  let index = match set.notes[note] {
+    Some(index) => index,
+    None => { osc.state = State::Off; return; }
+  };
+
+  // Los tubos por tecla suenan en su tono, solo se corrige la frecuencia
+  // de muestreo.
+  let step = |rec: &Recording| {
+    let ratio = if set.keyed { 1.0 } else { freq / rec.freq };
+    (ratio * rec.sample_rate / fs) as f64
+  };
+
+  osc.recording = Some((set.clone(), index));
+  osc.play_pos = 0.0;
+  osc.play_step = step(&set.recordings[index]);
+  osc.play_sinc = sinc_for(osc.play_step);
+
+  osc.release_rec = set.releases[note];
+  osc.release_pos = 0.0;
+  osc.release_step = osc.release_rec.map(|r| step(&set.recordings[r])).unwrap_or(0.0);
+  osc.release_sinc = sinc_for(osc.release_step);
+  osc.fade = 1.0;
+  osc.fade_delta = 1.0 / (XFADE_S * fs);
+
+  osc.env = 0.0;
+  osc.env_time = 0.0;
+  osc.env_period = 1.0 / fs;
+}
+
+/// Avanza el envelope de una grabación un sample y devuelve su nivel.
+fn envelope (osc: &mut Osc, env: &Envelope, released: bool) -> f32 {
+  if released {
+    // Baja linealmente, release es lo que tarda desde el máximo
+    osc.env -= osc.env_period / env.release.max(osc.env_period);
+    osc.env = osc.env.max(0.0);
+    return osc.env;
+  }
+
+  let t = osc.env_time;
+  osc.env_time += osc.env_period;
+
+  let decay_start = env.attack + env.hold;
+  osc.env = if t < env.attack { t / env.attack }
+    else if t < decay_start { 1.0 }
+    else if t < decay_start + env.decay {
+      lerp(1.0, env.sustain, (t - decay_start) / env.decay)
+    } else { env.sustain };
+  osc.env
+}
+
+/// La grabación principal, o None si ya terminó.
+fn attack (set: &SampleSet, rec: &Recording, osc: &mut Osc, step: f64, released: bool) -> Option<f32> {
+  // Mientras la tecla está presionada se queda en el loop, y al soltarla
+  // sigue hasta el final de la grabación, a menos que haya un release
+  // aparte o que el loop siga en el release.
+  let keep_looping = !released || osc.release_rec.is_some() || rec.loop_release;
+  if let Some((start, end)) = rec.looped {
+    if keep_looping && osc.play_pos >= end as f64 {
+      osc.play_pos -= (end - start) as f64;
+    }
+  }
+
+  if osc.play_pos >= rec.data.len() as f64 { return None; }
+
+  let level = match rec.envelope {
+    Some(ref env) => envelope(osc, env, released),
+    None => 1.0,
+  };
+  if released && level == 0.0 { return None; }
+
+  let sample = set.interp(&rec.data, osc.play_pos, osc.play_sinc) * rec.gain * level;
+  osc.play_pos += step;
+  Some(sample)
+}
+
+pub fn clock (osc: &mut Osc, wind: &Wind) -> f32 {
+  let pitch = wind.pitch as f64;
+
+  // Se saca la grabación del osc mientras tanto, para poder pasarle el
+  // osc a las demás funciones.
+  let (set, index) = match osc.recording.take() {
+    Some(recording) => recording,
+    None => return 0.0,
+  };
+
+  let sample = {
+    let rec = &set.recordings[index];
+    let released = osc.state == State::Release;
+
+    // Con un release aparte, al soltar la tecla el ataque se cruza con
+    // el release.
+    let mut sample = 0.0;
+    let mut release_done = true;
+    if let (true, Some(r)) = (released, osc.release_rec) {
+      let release = &set.recordings[r];
+      if osc.release_pos < release.data.len() as f64 {
+        sample += set.interp(&release.data, osc.release_pos, osc.release_sinc) * release.gain * (1.0 - osc.fade);
+        osc.release_pos += osc.release_step * pitch;
+        release_done = false;
+      }
+      osc.fade = (osc.fade - osc.fade_delta).max(0.0);
+    }
+
+    let step = osc.play_step * pitch;
+    let attack = if osc.fade > 0.0 { attack(&set, rec, osc, step, released) } else { None };
+
+    match attack {
+      Some(s) => sample += s * osc.fade,
+      None => if release_done { osc.state = State::Off; },
+    }
+    sample
+  };
+
+  osc.recording = Some((set, index));
+  sample
+}
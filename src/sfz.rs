@@ -0,0 +1,293 @@
+use std::io::{self, Read, Write};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+
+use sampled::{Envelope, LoadError};
+
+/*
+
+Lee instrumentos SFZ para usarlos como rangos grabados. Un SFZ es texto
+con encabezados (<global>, <group>, <region>...) seguidos de opcodes
+`nombre=valor`. Cada <region> es una grabación, y hereda los opcodes de
+los encabezados anteriores: <global>, luego <master>, luego <group>.
+
+Solo se soporta lo que sirve para un rango de órgano: qué teclas cubre
+cada grabación y en qué tono está, los loops, el envelope de volumen y
+las grabaciones que suenan al soltar la tecla. Como el órgano no tiene
+velocidad, de las capas de velocidad solo se usa la más fuerte. Todo lo
+demás se ignora, pero se reporta por stderr para que se sepa por qué el
+instrumento no suena como debería.
+
+*/
+
+/// Cómo se usa el loop de una región. Los puntos del loop, si el SFZ los
+/// da, si no se usan los del WAV.
+pub enum LoopMode {
+  NoLoop,
+  /// Solo mientras la tecla está presionada
+  Sustain(Option<(usize, usize)>),
+  /// También durante el release del envelope
+  Continuous(Option<(usize, usize)>),
+}
+
+pub struct Region {
+  pub sample: PathBuf,
+  pub lokey: u8,
+  pub hikey: u8,
+  /// La nota en la que suena la grabación, con la afinación
+  pub keycenter: f32,
+  pub gain: f32,
+  pub looped: LoopMode,
+  pub envelope: Envelope,
+  /// Si suena al soltar la tecla en vez de al presionarla
+  pub release: bool,
+}
+
+const SUPPORTED: &'static [&'static str] = &[
+  "sample", "default_path",
+  "lokey", "hikey", "key", "pitch_keycenter", "tune", "transpose", "volume",
+  "lovel", "hivel",
+  "loop_mode", "loop_start", "loop_end", "loopstart", "loopend",
+  "trigger",
+  "ampeg_attack", "ampeg_hold", "ampeg_decay", "ampeg_sustain", "ampeg_release",
+];
+
+const HEADERS: &'static [&'static str] = &["control", "global", "master", "group", "region"];
+
+/// Reporta lo que el SFZ tiene pero no se usa, una vez por cada cosa.
+struct Warnings<'a> {
+  path: &'a Path,
+  seen: Vec<String>,
+}
+
+impl<'a> Warnings<'a> {
+  fn warn (&mut self, msg: String) {
+    if self.seen.contains(&msg) { return; }
+    let _ = writeln!(&mut io::stderr(), "[organ] SFZ {}: {}", self.path.display(), msg);
+    self.seen.push(msg);
+  }
+}
+
+/// Una nota como número midi o como nombre, c4 es 60.
+fn parse_note (value: &str) -> Option<u8> {
+  if let Ok(note) = value.parse::<i32>() {
+    return if note >= 0 && note <= 127 { Some(note as u8) } else { None };
+  }
+
+  let lower = value.to_lowercase();
+  let mut chars = lower.chars();
+  let mut note = match chars.next() {
+    Some('c') => 0, Some('d') => 2, Some('e') => 4, Some('f') => 5,
+    Some('g') => 7, Some('a') => 9, Some('b') => 11,
+    _ => return None,
+  };
+
+  let rest = chars.as_str();
+  let octave = if rest.starts_with('#') { note += 1; &rest[1..] }
+    else if rest.starts_with('b') && rest.len() > 1 { note -= 1; &rest[1..] }
+    else { rest };
+
+  match octave.parse::<i32>() {
+    Ok(octave) => {
+      let note = (octave + 1) * 12 + note;
+      if note >= 0 && note <= 127 { Some(note as u8) } else { None }
+    },
+    Err(_) => None,
+  }
+}
+
+type Opcodes = HashMap<String, String>;
+
+/// Separa el texto en encabezados y opcodes. Los valores pueden tener
+/// espacios (las rutas de sample los tienen), así que un valor sigue
+/// hasta el siguiente opcode o encabezado.
+fn parse (text: &str, warnings: &mut Warnings) -> Vec<(String, Opcodes)> {
+  let mut sections: Vec<(String, Opcodes)> = Vec::new();
+
+  for line in text.lines() {
+    let line = match line.find("//") {
+      Some(i) => &line[..i],
+      None => line,
+    };
+
+    if line.trim_left().starts_with('#') {
+      warnings.warn(format!("unsupported directive '{}' ignored", line.trim()));
+      continue;
+    }
+
+    // Los encabezados pueden estar pegados a los opcodes
+    let line = line.replace('<', " <").replace('>', "> ");
+
+    let mut last: Option<String> = None;
+    for token in line.split_whitespace() {
+      if token.starts_with('<') && token.ends_with('>') {
+        let header = token[1 .. token.len()-1].to_lowercase();
+        if !HEADERS.contains(&&header[..]) {
+          warnings.warn(format!("unsupported header <{}> ignored", header));
+        }
+        sections.push((header, HashMap::new()));
+        last = None;
+        continue;
+      }
+
+      let opcodes = match sections.last_mut() {
+        Some(&mut (_, ref mut opcodes)) => opcodes,
+        None => continue,
+      };
+
+      match token.find('=') {
+        Some(i) => {
+          let name = token[..i].to_lowercase();
+          opcodes.insert(name.clone(), token[i+1..].to_string());
+          last = Some(name);
+        },
+        // Un pedazo de un valor con espacios
+        None => if let Some(ref name) = last {
+          let value = opcodes.entry(name.clone()).or_insert_with(String::new);
+          value.push(' ');
+          value.push_str(token);
+        },
+      }
+    }
+  }
+
+  sections
+}
+
+/// Un opcode numérico. Los valores que no se pueden leer se reportan y se
+/// usa el valor por defecto.
+fn number (opcodes: &Opcodes, name: &str, default: f32, warnings: &mut Warnings) -> f32 {
+  match opcodes.get(name) {
+    Some(value) => match value.parse() {
+      Ok(number) => number,
+      Err(_) => {
+        warnings.warn(format!("invalid value for '{}': '{}'", name, value));
+        default
+      },
+    },
+    None => default,
+  }
+}
+
+/// Lee los opcodes de una región, ya con todo lo que hereda.
+fn region (
+  opcodes: &Opcodes,
+  dir: &Path,
+  warnings: &mut Warnings,
+) -> Option<Region> {
+  let mut names: Vec<&String> = opcodes.keys().collect();
+  names.sort();
+  for name in names {
+    if !SUPPORTED.contains(&&name[..]) {
+      warnings.warn(format!("unsupported opcode '{}' ignored", name));
+    }
+  }
+
+  let get = |name: &str| opcodes.get(name).map(|v| &v[..]);
+  let note = |name: &str, default: u8| get(name).and_then(parse_note).unwrap_or(default);
+
+  // Solo la capa de velocidad más fuerte
+  if number(opcodes, "hivel", 127.0, warnings) < 127.0 { return None; }
+
+  let sample = match get("sample") {
+    Some(sample) => sample,
+    None => {
+      warnings.warn("region without sample ignored".to_string());
+      return None;
+    },
+  };
+  let path = format!("{}{}", get("default_path").unwrap_or(""), sample).replace('\\', "/");
+
+  let key = get("key").and_then(parse_note);
+  let lokey = note("lokey", key.unwrap_or(0));
+  let hikey = note("hikey", key.unwrap_or(127));
+  let keycenter = note("pitch_keycenter", key.unwrap_or(60)) as f32
+    - number(opcodes, "transpose", 0.0, warnings)
+    - number(opcodes, "tune", 0.0, warnings) / 100.0;
+
+  let start = number(opcodes, "loop_start", number(opcodes, "loopstart", -1.0, warnings), warnings);
+  let end = number(opcodes, "loop_end", number(opcodes, "loopend", -1.0, warnings), warnings);
+  // loop_end es el último sample del loop
+  let points = if start >= 0.0 && end > start { Some((start as usize, end as usize + 1)) } else { None };
+
+  let looped = match get("loop_mode") {
+    Some("no_loop") => LoopMode::NoLoop,
+    Some("loop_sustain") => LoopMode::Sustain(points),
+    Some("loop_continuous") | None => LoopMode::Continuous(points),
+    Some(mode) => {
+      warnings.warn(format!("unsupported loop_mode '{}', playing without loop", mode));
+      LoopMode::NoLoop
+    },
+  };
+
+  let release = match get("trigger") {
+    Some("release") | Some("release_key") => true,
+    Some("attack") | Some("first") | Some("legato") | None => false,
+    Some(trigger) => {
+      warnings.warn(format!("unsupported trigger '{}', using attack", trigger));
+      false
+    },
+  };
+
+  let envelope = Envelope {
+    attack: number(opcodes, "ampeg_attack", 0.0, warnings),
+    hold: number(opcodes, "ampeg_hold", 0.0, warnings),
+    decay: number(opcodes, "ampeg_decay", 0.0, warnings),
+    sustain: number(opcodes, "ampeg_sustain", 100.0, warnings) / 100.0,
+    release: number(opcodes, "ampeg_release", 0.001, warnings),
+  };
+
+  Some(Region {
+    sample: dir.join(path),
+    lokey: lokey.min(hikey),
+    hikey: hikey,
+    keycenter: keycenter,
+    gain: 10_f32.powf(number(opcodes, "volume", 0.0, warnings) / 20.0),
+    looped: looped,
+    envelope: envelope,
+    release: release,
+  })
+}
+
+/// Lee las regiones de un SFZ. Los samples los lee quien las usa.
+pub fn load (path: &Path) -> Result<Vec<Region>, LoadError> {
+  let mut bytes = Vec::new();
+  File::open(path)
+    .and_then(|mut file| file.read_to_end(&mut bytes))
+    .map_err(|err| LoadError::Io(path.to_path_buf(), err))?;
+  let text = String::from_utf8_lossy(&bytes);
+
+  let mut warnings = Warnings { path: path, seen: Vec::new() };
+  let dir = path.parent().unwrap_or(Path::new("."));
+
+  // Los opcodes de cada nivel, se reemplazan al encontrar otro
+  // encabezado del mismo nivel.
+  let mut control = Opcodes::new();
+  let mut global = Opcodes::new();
+  let mut master = Opcodes::new();
+  let mut group = Opcodes::new();
+
+  let mut regions = Vec::new();
+  for (header, opcodes) in parse(&text, &mut warnings) {
+    match &header[..] {
+      "control" => control = opcodes,
+      "global" => { global = opcodes; master.clear(); group.clear(); },
+      "master" => { master = opcodes; group.clear(); },
+      "group" => group = opcodes,
+      "region" => {
+        let mut all = control.clone();
+        for level in [&global, &master, &group, &opcodes].iter() {
+          all.extend(level.iter().map(|(k, v)| (k.clone(), v.clone())));
+        }
+        if let Some(region) = region(&all, dir, &mut warnings) {
+          regions.push(region);
+        }
+      },
+      _ => {},
+    }
+  }
+
+  if regions.is_empty() { return Err(LoadError::Empty(path.to_path_buf())); }
+  Ok(regions)
+}
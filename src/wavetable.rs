@@ -0,0 +1,144 @@
+use helpers::*;
+
+/*
+
+Una tabla de onda (wavetable) guarda un período de una onda, y el tubo la
+lee en vez de calcular la onda en cada sample. Para que no haya aliasing la
+onda se guarda varias veces, cada vez con menos armónicos: las notas agudas
+leen una tabla con pocos armónicos, y las graves una con todos.
+
+Las tablas se construyen sumando senos, así que solo tienen los armónicos
+que se les pide. Pueden salir de un espectro, o de una onda cualquiera
+que se analiza con una transformada de Fourier.
+
+*/
+
+/// Samples de cada tabla. Hay uno más al final, igual al primero, para
+/// interpolar sin revisar el borde.
+const TABLE_SIZE: usize = 2048;
+
+/// Parte de la frecuencia de muestreo que pueden ocupar los armónicos,
+/// con margen para que el viento suba un poco la afinación.
+const BAND_LIMIT: f32 = 0.45;
+
+/// Máximo de armónicos que se guardan de una onda analizada. Alcanza
+/// hasta Nyquist para una fundamental de 43Hz a 44.1kHz.
+const MAX_HARMONICS: usize = 512;
+
+/// Samples con que se analiza una onda. Son muchos más que los armónicos
+/// que se guardan, para que los que no se guardan no se doblen sobre los
+/// que sí (aliasing del análisis).
+const ANALYSIS_SIZE: usize = 4096;
+
+/// Los armónicos más débiles que esto (-80dB) no se guardan.
+const MIN_HARMONIC: f32 = 0.0001;
+
+/// Un seno de n samples, para sumar armónicos sin calcular senos.
+fn sine_table (n: usize) -> Vec<f32> {
+  (0..n).map(|i| sin01(i as f32 / n as f32)).collect()
+}
+
+/// La tabla que tiene más armónicos sin pasar de Nyquist para una
+/// frecuencia. Un Wavetable puede tener menos tablas, ver read.
+pub fn table_for (freq: f32, fs: f32) -> usize {
+  let allowed = (BAND_LIMIT * fs / freq).max(1.0);
+  allowed.log2().floor() as usize
+}
+
+pub struct Wavetable {
+  /// La tabla i tiene hasta 2^i armónicos, la última los tiene todos.
+  tables: Vec<Vec<f32>>,
+}
+
+impl Wavetable {
+  /// Las tablas de una onda con las amplitudes de armónicos dadas, la
+  /// primera es la fundamental. Se normalizan con el mismo factor, el de
+  /// la tabla completa, para que el volumen no salte de una tabla a otra.
+  pub fn from_spectrum (amplitudes: &[f32]) -> Wavetable {
+    let partials: Vec<(f32, f32)> = amplitudes.iter().map(|&a| (a, 0.0)).collect();
+    let mut wavetable = Wavetable::from_partials(0.0, &partials);
+
+    let peak = wavetable.tables.last().unwrap().iter()
+      .fold(0.0, |peak: f32, x| peak.max(x.abs()));
+    if peak > 0.0 {
+      for table in wavetable.tables.iter_mut() {
+        for x in table.iter_mut() { *x /= peak; }
+      }
+    }
+    wavetable
+  }
+
+  /// Las tablas de un período de una onda, dada como función de la fase
+  /// de 0 a 1. No se normaliza, la onda suena igual que la función.
+  pub fn from_wave<F: Fn(f32) -> f32> (wave: F) -> Wavetable {
+    let sine = sine_table(ANALYSIS_SIZE);
+    let samples: Vec<f32> = (0..ANALYSIS_SIZE)
+      .map(|i| wave(i as f32 / ANALYSIS_SIZE as f32))
+      .collect();
+
+    let n = ANALYSIS_SIZE as f32;
+    let dc = samples.iter().sum::<f32>() / n;
+
+    let mut partials: Vec<(f32, f32)> = (1 .. MAX_HARMONICS+1).map(|h| {
+      let (mut s, mut c) = (0.0, 0.0);
+      for (i, &x) in samples.iter().enumerate() {
+        let k = h*i % ANALYSIS_SIZE;
+        s += x * sine[k];
+        c += x * sine[(k + ANALYSIS_SIZE/4) % ANALYSIS_SIZE];
+      }
+      (2.0*s/n, 2.0*c/n)
+    }).collect();
+
+    // Los armónicos débiles del final no valen la memoria que ocupan
+    let count = partials.iter()
+      .rposition(|&(s, c)| s.abs().max(c.abs()) > MIN_HARMONIC)
+      .map(|i| i+1).unwrap_or(0);
+    partials.truncate(count);
+
+    Wavetable::from_partials(dc, &partials)
+  }
+
+  /// Suma los armónicos, como (seno, coseno) desde la fundamental.
+  fn from_partials (dc: f32, partials: &[(f32, f32)]) -> Wavetable {
+    let sine = sine_table(TABLE_SIZE);
+    let count = partials.len().max(1);
+
+    let mut tables = Vec::new();
+    let mut limit = 1;
+    loop {
+      let table = (0 .. TABLE_SIZE + 1).map(|i| {
+        let i = i % TABLE_SIZE;
+        dc + partials.iter().take(limit).enumerate().map(|(h, &(s, c))| {
+          let k = (h+1)*i % TABLE_SIZE;
+          s * sine[k] + c * sine[(k + TABLE_SIZE/4) % TABLE_SIZE]
+        }).sum::<f32>()
+      }).collect();
+      tables.push(table);
+
+      if limit >= count { break; }
+      limit *= 2;
+    }
+
+    Wavetable { tables: tables }
+  }
+
+  /// Una tabla, si no existe la última, que tiene todos los armónicos.
+  pub fn table (&self, table: usize) -> &[f32] {
+    &self.tables[table.min(self.tables.len() - 1)]
+  }
+
+  /// Lee una tabla en una fase de 0 a 1, interpolando entre samples.
+  pub fn read (&self, table: usize, phase: f32) -> f32 {
+    let (i, frac) = position(phase);
+    let table = self.table(table);
+    lerp(table[i], table[i+1], frac)
+  }
+}
+
+/// El sample de las tablas en una fase de 0 a 1, y la fracción hasta el
+/// siguiente.
+pub fn position (phase: f32) -> (usize, f32) {
+  let pos = phase * TABLE_SIZE as f32;
+  let i = (pos as usize).min(TABLE_SIZE - 1);
+  (i, pos - i as f32)
+}
@@ -0,0 +1,170 @@
+use helpers::*;
+
+/*
+
+El viento de un órgano de tubos sale de un fuelle a un depósito (reservoir)
+y de ahí al secreto (wind chest), sobre el que están parados los tubos.
+Cuando suenan muchos tubos el consumo de aire sube y la presión baja un
+poco, hasta que el depósito compensa. Pero el depósito tiene masa y un
+resorte, así que no compensa de inmediato, y si está poco amortiguado la
+presión rebota un par de veces antes de estabilizarse.
+
+Con menos presión los tubos suenan más bajo, un poco más graves y más
+opacos. Todos los tubos comparten el mismo viento, así que cuando se toca
+un acorde grande todo el órgano se hunde junto.
+
+*/
+
+/// Tiempo característico del depósito, en segundos.
+const RESERVOIR_S: f32 = 0.06;
+
+/// Caída de presión máxima con la profundidad al máximo.
+const MAX_SAG: f32 = 0.12;
+
+/// Consumo con el que la presión cae la mitad de la caída máxima. Un tubo
+/// a todo volumen consume 1.
+const LOAD_REF: f32 = 12.0;
+
+// Amortiguamiento del depósito con la estabilidad en 0 y en 1.
+const MIN_DAMPING: f32 = 0.15;
+const MAX_DAMPING: f32 = 1.2;
+
+// Cómo responden los tubos a la presión, como exponentes de la presión
+// relativa. El tono de un tubo labial sube muy poco con la presión.
+const PITCH_EXP: f32 = 0.08;
+const AMP_EXP: f32 = 1.0;
+const BRIGHT_EXP: f32 = 1.5;
+
+/// El viento que le llega a los tubos en un sample, relativo a la presión
+/// normal. Todo en 1 es un viento perfectamente estable.
+#[derive(Clone, Copy)]
+pub struct Wind {
+  pub pressure: f32,
+  pub pitch: f32,
+  pub amp: f32,
+  pub bright: f32,
+  /// log2 de bright, para las tablas de los tubos
+  pub bright_log2: f32,
+}
+
+impl Wind {
+  pub fn new (pressure: f32) -> Wind {
+    Wind {
+      pressure: pressure,
+      pitch: pressure.powf(PITCH_EXP),
+      amp: pressure.powf(AMP_EXP),
+      bright: pressure.powf(BRIGHT_EXP),
+      bright_log2: BRIGHT_EXP * pressure.log2(),
+    }
+  }
+}
+
+impl Default for Wind {
+  fn default () -> Wind { Wind::new(1.0) }
+}
+
+pub struct Chest {
+  sample_rate: f32,
+
+  /// Qué tan amortiguado está el depósito
+  stability: f32,
+  /// Qué tanto baja la presión con el consumo
+  depth: f32,
+
+  omega: f32,
+  damping: f32,
+
+  pressure: f32,
+  velocity: f32,
+}
+
+impl Chest {
+  pub fn new () -> Chest {
+    let mut chest = Chest {
+      sample_rate: 44100.0,
+      stability: 0.5,
+      depth: 0.0,
+      omega: 0.0,
+      damping: 0.0,
+      pressure: 1.0,
+      velocity: 0.0,
+    };
+    chest.recalc();
+    chest
+  }
+
+  pub fn set_sample_rate (&mut self, fs: f32) {
+    self.sample_rate = fs;
+    self.recalc();
+  }
+
+  pub fn set_stability (&mut self, value: f32) {
+    self.stability = value;
+    self.recalc();
+  }
+
+  pub fn set_depth (&mut self, value: f32) { self.depth = value; }
+
+  fn recalc (&mut self) {
+    // Por sample, así el clock no tiene que dividir
+    self.omega = 1.0 / (RESERVOIR_S * self.sample_rate);
+    self.damping = 2.0 * lerp(MIN_DAMPING, MAX_DAMPING, self.stability) * self.omega;
+  }
+
+  /// El viento en este momento.
+  pub fn wind (&self) -> Wind { Wind::new(self.pressure) }
+
+  /// Avanza un sample con el consumo actual de los tubos.
+  pub fn clock (&mut self, load: f32) {
+    let target = 1.0 - self.depth * MAX_SAG * load / (load + LOAD_REF);
+
+    // Un resorte amortiguado hacia la presión de equilibrio
+    let force = self.omega * self.omega * (target - self.pressure);
+    self.velocity += force - self.damping * self.velocity;
+    self.pressure += self.velocity;
+  }
+}
+
+// Rango de velocidad del trémolo, en Hz
+const MIN_TREMULANT: f32 = 4.0;
+const MAX_TREMULANT: f32 = 8.0;
+
+/// Variación máxima de la presión del trémolo, relativa a la presión.
+const TREMULANT_DEPTH: f32 = 0.2;
+
+/// El trémolo de un órgano de tubos es una válvula que abre y cierra el
+/// paso del viento a una división, así que hace oscilar la presión. Con
+/// la presión cambian el volumen, el tono y el brillo de los tubos, pero
+/// no el hammond.
+pub struct Tremulant {
+  sample_rate: f32,
+
+  pub speed: f32,
+  pub depth: f32,
+
+  phase: f32,
+}
+
+impl Tremulant {
+  pub fn new () -> Tremulant {
+    Tremulant {
+      sample_rate: 44100.0,
+      speed: 0.0,
+      depth: 0.0,
+      phase: 0.0,
+    }
+  }
+
+  pub fn set_sample_rate (&mut self, fs: f32) { self.sample_rate = fs; }
+
+  /// Modula el viento que viene del secreto.
+  pub fn clock (&mut self, wind: &Wind) -> Wind {
+    if self.depth == 0.0 { return *wind; }
+
+    let freq = lerp(MIN_TREMULANT, MAX_TREMULANT, self.speed);
+    self.phase = mod1(self.phase + freq/self.sample_rate);
+
+    let m = 1.0 + sin01(self.phase) * self.depth * TREMULANT_DEPTH;
+    Wind::new(wind.pressure * m)
+  }
+}